@@ -0,0 +1,17 @@
+use std::process::Command;
+
+// Short git hash for the Boot frame, left empty when building outside a git checkout
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=8", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=LPLORA_GIT_HASH={}", hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=LPLORA_WATCHDOG_TIMEOUT_MS");
+}
@@ -1,6 +1,8 @@
 MEMORY
 {
   /* See section 4.3.1 "Flash memory organization" in the reference manual */
-  FLASH : ORIGIN = 0x8000000, LENGTH = 256k
+  /* Last 4 pages (8K) are kept for the settings store, see src/settings.rs */
+  FLASH : ORIGIN = 0x8000000, LENGTH = 248k
+  SETTINGS : ORIGIN = 0x803E000, LENGTH = 8k
   RAM : ORIGIN = 0x20000000, LENGTH = 64K
 }
\ No newline at end of file
@@ -2,6 +2,16 @@
 #![no_std]
 
 use lplora as _; // global logger + panicking-behavior + memory layout
+use rtic_monotonics::systick::prelude::*;
+
+// Every delay in here is 100ms or more, a 10ms tick does fine and interrupts the core 10 times less than 1ms would
+const MONO_HZ: u32 = 100;
+systick_monotonic!(Mono, MONO_HZ);
+
+/// Milliseconds since boot, wraps around at `u32::MAX` like everything timing off it expects
+fn now_ms() -> u32 {
+    Mono::now().ticks().wrapping_mul(1000 / MONO_HZ)
+}
 
 // TODO(7) Configure the `rtic::app` macro
 #[rtic::app(
@@ -12,26 +22,59 @@ use lplora as _; // global logger + panicking-behavior + memory layout
 )]
 mod app {
     use cortex_m::asm::wfi;
+    use cortex_m::delay::Delay;
     use cortex_m::interrupt::CriticalSection;
     use cortex_m::prelude::*;
     use heapless::spsc::Queue;
-    use lplora::constants::{CacheQueue, RFSW_GPIO_OUTPUT_ARGS, SLEEP_CFG, SLIP_END, SLIP_START};
+    use lplora::constants::{CacheQueue, RFSW_GPIO_OUTPUT_ARGS, SLIP_END, SLIP_START};
+    use lplora::error::{Error, Recovery};
+    use lplora::flow_status::{FlowStatus, FLOW_STATUS_INTERVAL_MS};
+    use lplora::packet::radio_bpsk_cfg::RadioBpskConfigurator;
     use lplora::packet::radio_freq_cfg::RadioFreqConfigurator;
     use lplora::packet::radio_gfsk_cfg::RadioGfskConfigurator;
     use lplora::packet::radio_lora_cfg::RadioLoraConfigurator;
     use lplora::packet::radio_phy_cfg::RadioPhyConfigurator;
+    use lplora::packet::radio_queue_send::RadioQueueSendCommand;
     use lplora::packet::radio_rx_cmd::RadioRxCommand;
+    use lplora::packet::radio_send_ex::{RadioSendExCommand, TxOverrides};
+    use lplora::packet::radio_sleep_cmd::RadioSleepCommand;
+    use lplora::packet::range_test_cmd::RangeTestCommand;
+    use lplora::packet::rx_forward_cmd::SetRxForwardingCommand;
+    use lplora::packet::settings_cmd::SettingsSaveCommand;
+    use lplora::packet::status_cmd::GetStatusCommand;
+    use lplora::packet::uart_baud_cmd::SetBaudRateCommand;
     use lplora::packet::uart_pkt_decoder::UartPacketDecoder;
     use lplora::packet::uart_pkt_encoder::UartPacketEncoder;
-    use lplora::packet::UartPacketType;
+    use lplora::packet::{UartPacketError, UartPacketType};
     use lplora::power::enter_stop2_mode;
-    use lplora::radio::{handle_radio_rx_done, setup_radio, start_radio_rx, start_radio_tx};
+    use lplora::radio::{
+        encode_radio_fault, read_radio_op_error, read_radio_packet, reset_radio, rf_switch_rx, rf_switch_tx,
+        set_radio_to_standby, setup_radio, start_radio_rx, start_radio_tx, PacketParams, RadioFaultKind,
+    };
+    use lplora::radio_cfg::{apply_factory_defaults, wake_radio, ActiveModulation, ActiveRadioConfig};
+    use lplora::radio_recovery::{encode_recovery_event, recover_radio, RadioHealth, RecoveryCause, RecoveryResult};
+    use lplora::radio_state::{RadioEvent, RadioMode, RadioState};
+    use lplora::range_test::{RangeTest, RangeTestRole, RangeTestRx, RANGE_TEST_ECHO_LEN};
+    use lplora::rx_queue::RxPacketQueue;
+    use lplora::settings::{erase_settings, load_settings, save_settings, SettingsRecord};
+    use lplora::stats::{self, Stat};
+    use lplora::status::{encode_boot, ResetCause, StatusAdc, StatusReporter};
+    use lplora::tx_queue::{TxQueue, TxStatus};
+    use lplora::uart_baud::{set_lpuart_baud, BaudState, BAUD_FALLBACK_MS, DEFAULT_BAUD};
+    use lplora::uart_dma::UartDma;
+    use lplora::uart_framer::{FrameTimer, FRAME_TIMEOUT_MS};
+    use lplora::watchdog::{
+        apply_watchdog_option, feed_watchdog, idle_alive, radio_alive, start_watchdog, uart_alive, WATCHDOG_CHECK_MS,
+        WATCHDOG_TIMEOUT_MS,
+    };
+    use rtic_monotonics::systick::prelude::*;
     use stm32wlxx_hal::gpio::pins::{B8, C13};
     use stm32wlxx_hal::pac::Interrupt;
     use stm32wlxx_hal::pwr::{enter_lprun_msi, LprunRange};
     use stm32wlxx_hal::spi::{SgMiso, SgMosi};
-    use stm32wlxx_hal::subghz::{Irq, StandbyClk, SubGhz};
+    use stm32wlxx_hal::subghz::SubGhz;
     use stm32wlxx_hal::{
+        adc::{self, Adc},
         gpio::{pins, Output, PortA, PortB, PortC},
         pac::Peripherals,
         rcc, uart,
@@ -50,12 +93,41 @@ mod app {
         rf_sw_2: Output<C13>,
 
         radio: SubGhz<SgMiso, SgMosi>,
+
+        #[lock_free]
+        radio_state: RadioState,
+
+        #[lock_free]
+        radio_health: RadioHealth,
+
+        #[lock_free]
+        range_test: RangeTest,
+
+        #[lock_free]
+        pkt_params: Option<PacketParams>,
+
+        #[lock_free]
+        active_cfg: ActiveRadioConfig,
+
+        #[lock_free]
+        tx_restore: Option<TxOverrides>,
+
+        #[lock_free]
+        tx_queue: TxQueue,
+
+        #[lock_free]
+        rx_queue: RxPacketQueue,
     }
 
     // Local resources go here
     #[local]
     struct Local {
         uart: LpUart<pins::A3, pins::A2>,
+        baud: BaudState,
+        uart_dma: UartDma,
+        flow_status: FlowStatus,
+        frame_timer: FrameTimer,
+        status: StatusReporter,
     }
 
     #[init]
@@ -64,6 +136,9 @@ mod app {
 
         let mut dp = ctx.device;
         let cs = unsafe { &CriticalSection::new() };
+        let watchdog_option_ok = apply_watchdog_option();
+        let reset_cause = ResetCause::read_and_clear();
+        start_watchdog(WATCHDOG_TIMEOUT_MS);
 
         unsafe {
             rcc::set_sysclk_msi(
@@ -92,11 +167,11 @@ mod app {
         while dp.RCC.bdcr.read().lsesysrdy().is_not_ready() {}
 
         let dp_dirty = unsafe { Peripherals::steal() };
-        let uart: LpUart<pins::A3, pins::A2> = LpUart::new(dp.LPUART, 9600, uart::Clk::Lse, &mut dp.RCC)
+        let uart: LpUart<pins::A3, pins::A2> = LpUart::new(dp.LPUART, DEFAULT_BAUD, uart::Clk::Lse, &mut dp.RCC)
             .enable_rx(io_a.a3, cs)
             .enable_tx(io_a.a2, cs);
 
-        // Enable Rx and Tx interrupt
+        // Rx and Tx data go through DMA, so only idle line and Tx complete interrupts are needed
         dp_dirty.LPUART.cr1.modify(|_, w| {
             w.te()
                 .set_bit()
@@ -104,36 +179,88 @@ mod app {
                 .set_bit()
                 .tcie()
                 .set_bit()
-                .rxneie()
+                .idleie()
                 .set_bit()
+                .rxneie()
+                .clear_bit()
                 .uesm()
                 .set_bit()
-                .fifoen()
-                .clear_bit() // We DO NOT want FIFO mode for now
         });
+        let uart_dma = UartDma::init();
 
         // Enable debug domain in STOP mode
         if cfg!(debug_assertions) {
             defmt::info!("Enable debug at STOP mode");
             dp.DBGMCU.cr.modify(|_, w| w.dbg_stop().set_bit());
+            dp.DBGMCU.apb1fzr1.modify(|_, w| w.dbg_iwdg_stop().set_bit()); // Don't reset while halted at a breakpoint
         } else {
             dp.DBGMCU.cr.modify(|_, w| w.dbg_stop().clear_bit());
         }
 
         // Set up RF Switch GPIOs
-        let rf_sw_1 = Output::new(io_b.b8, &RFSW_GPIO_OUTPUT_ARGS, cs);
-        let rf_sw_2 = Output::new(io_c.c13, &RFSW_GPIO_OUTPUT_ARGS, cs);
+        let mut rf_sw_1 = Output::new(io_b.b8, &RFSW_GPIO_OUTPUT_ARGS, cs);
+        let mut rf_sw_2 = Output::new(io_c.c13, &RFSW_GPIO_OUTPUT_ARGS, cs);
 
-        let uart_tx_q: CacheQueue = Queue::new();
+        let mut uart_tx_q: CacheQueue = Queue::new();
         let uart_rx_q: CacheQueue = Queue::new();
 
         let mut radio = SubGhz::new(dp.SPI3, &mut dp.RCC);
         setup_radio(&mut radio).unwrap();
 
+        // Start from the default profile so the module works without any host config,
+        // then pick up where the host left off last time it saved
+        let mut radio_state = RadioState::new();
+        let mut active_cfg = ActiveRadioConfig::new();
+        let mut pkt_params: Option<PacketParams> = None;
+        let mut baud = BaudState::new(DEFAULT_BAUD);
+        if let Err(err) = apply_factory_defaults(&mut radio, &mut active_cfg, &mut pkt_params) {
+            defmt::error!("Init: default profile not applied: {:?}", err);
+        }
+
+        if let Some(record) = load_settings() {
+            if let Some(rate) = record.baud() {
+                set_lpuart_baud(rate);
+                baud = BaudState::new(rate);
+            }
+
+            match record.apply(&mut radio, &mut active_cfg, &mut pkt_params) {
+                Ok(_) => {
+                    if let Some(timeout_ms) = record.auto_rx_ms().filter(|_| active_cfg.can_receive()) {
+                        rf_switch_rx(&mut rf_sw_1, &mut rf_sw_2);
+                        if let Err(err) = start_radio_rx(&mut radio, &mut radio_state, pkt_params.as_ref(), timeout_ms)
+                        {
+                            defmt::error!("Init: auto Rx failed: {:?}", err);
+                        }
+                    }
+                }
+                Err(err) => defmt::error!("Init: stored settings not applied: {:?}", err),
+            }
+        }
+
+        // ADC calibration needs a delay, borrow SysTick for it before the monotonic takes it over
+        let mut delay = Delay::new(ctx.core.SYST, rcc::sysclk_hz(&dp.RCC));
+        let mut adc = Adc::new(dp.ADC, adc::Clk::PClk, &mut dp.RCC);
+        adc.calibrate(&mut delay);
+        let syst = delay.free();
+
         cortex_m::interrupt::free(|cs| unsafe {
             enter_lprun_msi(&mut dp.FLASH, &mut dp.PWR, &mut dp.RCC, LprunRange::Range1M, cs)
         });
 
+        // SysTick follows the core clock, so start it after we settled at LPRun
+        crate::Mono::start(syst, rcc::sysclk_hz(&dp.RCC));
+
+        radio_health_tick::spawn().ok();
+        flow_status_tick::spawn().ok();
+        watchdog_tick::spawn().ok();
+
+        // Goes out as soon as uart_task gets going
+        encode_boot(reset_cause, &mut uart_tx_q);
+        if !watchdog_option_ok {
+            Error::WatchdogOption.encode(&mut uart_tx_q);
+        }
+        rtic::pend(Interrupt::LPUART1);
+
         defmt::info!("Init setup complete!");
 
         (
@@ -143,16 +270,56 @@ mod app {
                 uart_rx_q,
                 rf_sw_1,
                 rf_sw_2,
+                radio_state,
+                radio_health: RadioHealth::new(),
+                range_test: RangeTest::new(),
+                pkt_params,
+                active_cfg,
+                tx_restore: None,
+                tx_queue: TxQueue::new(),
+                rx_queue: RxPacketQueue::new(),
+            },
+            Local {
+                uart,
+                baud,
+                uart_dma,
+                flow_status: FlowStatus::new(),
+                frame_timer: FrameTimer::new(),
+                status: StatusReporter::new(StatusAdc::new(adc), reset_cause),
             },
-            Local { uart },
         )
     }
 
-    #[task(binds = LPUART1, shared = [uart_rx_q, uart_tx_q, radio, rf_sw_1, rf_sw_2], local = [uart])]
+    #[task(binds = LPUART1, shared = [uart_rx_q, uart_tx_q, radio, rf_sw_1, rf_sw_2, radio_state, radio_health, range_test, pkt_params, active_cfg, tx_restore, tx_queue, rx_queue], local = [uart, baud, uart_dma, flow_status, frame_timer, status])]
     fn uart_task(ctx: uart_task::Context) {
         let uart_rx_queue = ctx.shared.uart_rx_q;
         let uart_tx_queue = ctx.shared.uart_tx_q;
-        let uart = ctx.local.uart;
+        let radio_state = ctx.shared.radio_state;
+        let radio_health = ctx.shared.radio_health;
+        let pkt_params = ctx.shared.pkt_params;
+        let active_cfg = ctx.shared.active_cfg;
+        let tx_restore = ctx.shared.tx_restore;
+        let tx_queue = ctx.shared.tx_queue;
+        let rx_queue = ctx.shared.rx_queue;
+        let range_test = ctx.shared.range_test;
+        let mut radio = ctx.shared.radio;
+        let mut rf_sw_1 = ctx.shared.rf_sw_1;
+        let mut rf_sw_2 = ctx.shared.rf_sw_2;
+        let baud = ctx.local.baud;
+        let uart_dma = ctx.local.uart_dma;
+        let flow_status = ctx.local.flow_status;
+        let frame_timer = ctx.local.frame_timer;
+        let status = ctx.local.status;
+
+        uart_alive();
+        baud.check_fallback(crate::now_ms());
+
+        // Host stopped halfway through a frame, don't wait for the next SLIP_START to get rid of it
+        if frame_timer.check_expired(crate::now_ms(), uart_rx_queue) {
+            resync_framer(Error::UartFrameTimeout, baud, uart_rx_queue, uart_tx_queue);
+        } else if !uart_rx_queue.is_empty() {
+            uart_frame_timeout::spawn().ok();
+        }
 
         let dp = unsafe { Peripherals::steal() };
         let isr = dp.LPUART.isr.read();
@@ -161,35 +328,97 @@ mod app {
                 "uart_task: LPUART_ISR indicate something screwed up: 0x{:x}",
                 isr.bits()
             );
+            for (flagged, err) in [
+                (isr.pe().bit_is_set(), uart::Error::Parity),
+                (isr.fe().bit_is_set(), uart::Error::Framing),
+                (isr.ne().bit_is_set(), uart::Error::Noise),
+                (isr.ore().bit_is_set(), uart::Error::Overrun),
+            ] {
+                if flagged {
+                    stats::count_uart_error(err);
+                }
+            }
+
+            let err = if isr.ore().bit_is_set() {
+                uart::Error::Overrun
+            } else if isr.fe().bit_is_set() {
+                uart::Error::Framing
+            } else if isr.ne().bit_is_set() {
+                uart::Error::Noise
+            } else {
+                uart::Error::Parity
+            };
             dp.LPUART.icr.write(|w| {
                 w.pecf().set_bit();
                 w.fecf().set_bit();
                 w.ncf().set_bit();
                 w.orecf().set_bit()
             });
+
+            // Whatever frame in progress has lost or garbled bytes now
+            resync_framer(Error::from(err), baud, uart_rx_queue, uart_tx_queue);
             return;
-        } else if isr.rxfne().bit_is_set() {
-            defmt::trace!("uart_task: LPUART_ISR RXNE set!");
-            let mut packet_ended: bool = false;
-            let recv_byte = uart.read().unwrap();
+        }
+
+        // Idle line ends an Rx burst, the bytes are in the DMA ring already
+        if isr.idle().bit_is_set() {
+            dp.LPUART.icr.write(|w| w.idlecf().set_bit());
+        }
+
+        // Tx goes first, the Rx handling below returns early all over the place
+        flow_status.encode_if_due(
+            crate::now_ms(),
+            uart_dma.rx_credits(uart_rx_queue),
+            rx_queue,
+            uart_tx_queue,
+        );
+        status.encode_if_due(crate::now_ms(), radio_state, active_cfg, uart_rx_queue, uart_tx_queue);
+        if rx_queue.has_pending() {
+            rx_queue.flush_into(uart_tx_queue);
+        }
+        if !uart_dma.tx_start(uart_tx_queue) && !uart_dma.tx_busy() && isr.tc().bit_is_set() {
+            dp.LPUART.icr.write(|w| w.tccf().set_bit());
+            defmt::trace!("uart_task: nothing left in Tx queue, TC cleared!");
+            if baud.apply_pending(crate::now_ms()) {
+                uart_baud_fallback::spawn().ok();
+            }
+        }
+
+        // DMA lapped us, so the frame in progress has a hole in it somewhere
+        if uart_dma.rx_check_overrun() {
+            stats::count_uart_error(uart::Error::Overrun);
+            resync_framer(Error::from(uart::Error::Overrun), baud, uart_rx_queue, uart_tx_queue);
+        }
+
+        // Take everything the DMA got so far, frames get handled as soon as their SLIP_END shows up
+        while let Some(recv_byte) = uart_dma.rx_pop() {
+            uart_dma.update_rx_hold(uart_rx_queue);
+
             defmt::trace!("Rx got 0x{:02x}", recv_byte);
-            match recv_byte {
+            let mut packet_ended: bool = false;
+            let enqueued = match recv_byte {
                 SLIP_START => {
                     defmt::info!("UART packet started");
-                    while !uart_rx_queue.is_empty() {
-                        uart_rx_queue.dequeue().unwrap();
-                    }
-
-                    uart_rx_queue.enqueue(recv_byte).unwrap();
+                    while uart_rx_queue.dequeue().is_some() {}
+                    uart_rx_queue.enqueue(recv_byte)
                 }
                 SLIP_END => {
                     defmt::info!("UART packet ended");
-                    uart_rx_queue.enqueue(recv_byte).unwrap();
                     packet_ended = true;
+                    uart_rx_queue.enqueue(recv_byte)
                 }
-                _ => {
-                    uart_rx_queue.enqueue(recv_byte).unwrap();
-                }
+                _ => uart_rx_queue.enqueue(recv_byte),
+            };
+
+            if enqueued.is_err() {
+                stats::count(Stat::UartRxOverflow);
+                resync_framer(Error::UartRxOverflow, baud, uart_rx_queue, uart_tx_queue);
+                continue;
+            }
+
+            if !packet_ended {
+                frame_timer.byte_received(crate::now_ms());
+                uart_frame_timeout::spawn().ok();
             }
 
             if packet_ended {
@@ -197,266 +426,719 @@ mod app {
                     Ok(p) => p,
                     Err(err) => {
                         defmt::error!("Something wrong when decode: {:?}", err);
-                        return;
+                        stats::count_packet_error(err);
+                        resync_framer(Error::from(err), baud, uart_rx_queue, uart_tx_queue);
+                        continue;
                     }
                 };
 
-                let (payload, len) = packet.get_payload();
-                let mut radio = ctx.shared.radio;
-                match packet.get_type() {
-                    UartPacketType::RadioSend => {
-                        defmt::info!("Got RadioSendPacket, len={}", len);
-                        (ctx.shared.rf_sw_1, ctx.shared.rf_sw_2).lock(|sw1, sw2| {
-                            sw1.set_level_low();
-                            sw2.set_level_high();
-                        });
-                        radio
-                            .lock(|r| start_radio_tx(r, &payload[0..(len as usize)], 5000))
-                            .unwrap();
-
-                        UartPacketEncoder::make_ack(uart_tx_queue);
-                        //rtic::pend(Interrupt::LPUART1); // Can we actually do this (two pends)???
+                stats::count(Stat::UartFramesRx);
+
+                // Frame is out of `uart_rx_q` now, tell the host how much it may send next
+                let credits = uart_dma.rx_credits(uart_rx_queue);
+
+                // Host got a valid frame through, so a new baud rate is good to keep.
+                // Only the rate gets saved, whatever radio config is active now is up to SettingsSave.
+                if let Some(rate) = baud.confirm() {
+                    let mut record = load_settings().unwrap_or_else(|| SettingsRecord::baud_only(rate));
+                    record.set_baud(Some(rate));
+                    if let Err(err) = save_settings(record) {
+                        Error::from(err).encode(uart_tx_queue);
                     }
-                    UartPacketType::Ping => {
-                        defmt::info!("Someone ping me!");
-                        UartPacketEncoder::make_pong(uart_tx_queue);
-                        rtic::pend(Interrupt::LPUART1);
+                }
+
+                // Anything touching the radio after a cold start sleep gets the old settings replayed first
+                let uses_radio = !matches!(
+                    packet.get_type(),
+                    UartPacketType::Ping
+                        | UartPacketType::GetRadioPhyConfig
+                        | UartPacketType::GetRadioFreqConfig
+                        | UartPacketType::GetRadioLoraConfig
+                        | UartPacketType::GetRadioGfskConfig
+                        | UartPacketType::GetRadioBpskConfig
+                        | UartPacketType::GetStats
+                        | UartPacketType::ResetStats
+                        | UartPacketType::GetStatus
+                        | UartPacketType::RadioGoSleep
+                        | UartPacketType::RangeTestStop
+                        | UartPacketType::SettingsSave
+                        | UartPacketType::SettingsErase
+                        | UartPacketType::SetBaudRate
+                        | UartPacketType::SetRxForwarding
+                        | UartPacketType::Restart
+                        | UartPacketType::EnterSleepStop2
+                );
+
+                // Handlers send their own reply, the NACK for a failed one goes out below
+                let handle_packet = || -> Result<(), Error> {
+                    if uses_radio && radio_state.config_lost() {
+                        radio.lock(|r| wake_radio(r, radio_state, active_cfg))?;
                     }
-                    UartPacketType::RadioGoIdle => {
-                        match radio.lock(|r| r.set_standby(StandbyClk::Rc)) {
-                            Ok(_) => {
-                                UartPacketEncoder::make_ack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+
+                    match packet.get_type() {
+                        UartPacketType::RadioSend => {
+                            let (payload, len) = packet.get_payload();
+                            defmt::info!("Got RadioSendPacket, len={}", len);
+                            // A queued packet on air has to finish first, or its TxDone gets mixed up with this one
+                            if radio_state.is_busy() || tx_queue.in_flight().is_some() {
+                                defmt::error!("Got RadioSend while radio busy: {:?}", radio_state.mode());
+                                return Err(Error::Rejected);
                             }
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+
+                            (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_tx);
+
+                            radio.lock(|r| -> Result<(), Error> {
+                                if let Some(overrides) = tx_restore.take() {
+                                    overrides.restore(r, active_cfg)?;
+                                }
+                                start_radio_tx(r, radio_state, pkt_params.as_ref(), &payload[0..(len as usize)], 5000)?;
+                                Ok(())
+                            })?;
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::RadioSendEx => {
+                            let cmd = RadioSendExCommand::try_from(packet)?;
+                            // A queued packet on air has to finish first, or its TxDone gets mixed up with this one
+                            if radio_state.is_busy() || tx_queue.in_flight().is_some() {
+                                defmt::error!("Got RadioSendEx while radio busy: {:?}", radio_state.mode());
+                                return Err(Error::Rejected);
                             }
-                        };
-                    }
-                    UartPacketType::RadioGoSleep => {
-                        match radio.lock(|r| unsafe { r.set_sleep(SLEEP_CFG) }) {
-                            Ok(_) => {
-                                UartPacketEncoder::make_ack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+
+                            let overrides = cmd.overrides();
+                            if !overrides.can_restore(active_cfg) {
+                                defmt::error!("Got RadioSendEx overriding something never configured");
+                                return Err(Error::Rejected);
                             }
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                            if !overrides.power_in_range(active_cfg) {
+                                defmt::error!("Got RadioSendEx with Tx power out of range for the PA");
+                                return Err(Error::from(UartPacketError::CorruptedError));
                             }
-                        };
-                    }
-                    UartPacketType::RadioRecvStart => {
-                        let cmd = match RadioRxCommand::try_from(packet) {
-                            Ok(cfg) => cfg,
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+
+                            // Previous overridden Tx hasn't finished yet, put things back before stacking new ones
+                            if let Some(prev) = tx_restore.take() {
+                                radio.lock(|r| prev.restore(r, active_cfg))?;
                             }
-                        };
 
-                        match radio.lock(|r| cmd.configure_radio(r)) {
-                            Ok(_) => {
-                                UartPacketEncoder::make_ack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                            defmt::info!("Got RadioSendEx, len={}", cmd.payload().len());
+                            (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_tx);
+
+                            let ret = radio.lock(|r| {
+                                overrides.apply(r, active_cfg)?;
+                                start_radio_tx(r, radio_state, pkt_params.as_ref(), cmd.payload(), 5000)
+                            });
+                            if let Err(err) = ret {
+                                let _ = radio.lock(|r| overrides.restore(r, active_cfg));
+                                return Err(Error::from(err));
                             }
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+
+                            if !overrides.is_empty() {
+                                *tx_restore = Some(overrides);
                             }
-                        };
-                    }
-                    UartPacketType::RadioPhyConfig => {
-                        let config = match RadioPhyConfigurator::try_from(packet) {
-                            Ok(cfg) => cfg,
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::RadioQueueSend => {
+                            let cmd = RadioQueueSendCommand::try_from(packet)?;
+                            if !tx_queue.push(cmd.id(), cmd.payload()) {
+                                return Err(Error::Rejected);
                             }
-                        };
 
-                        match radio.lock(|r| config.configure_radio(r)) {
-                            Ok(_) => {
-                                UartPacketEncoder::make_ack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                            // Ack first so that the host sees it before any Tx status of this packet
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                            if tx_queue.in_flight().is_none() && !radio_state.is_busy() {
+                                (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_tx);
+                                radio.lock(|r| tx_queue.start_next(r, radio_state, pkt_params.as_ref(), uart_tx_queue));
                             }
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                        }
+                        UartPacketType::Ping => {
+                            defmt::info!("Someone ping me!");
+                            UartPacketEncoder::make_pong(uart_tx_queue, credits);
+                        }
+                        UartPacketType::RadioGoIdle => {
+                            let aborted_tx = matches!(radio_state.mode(), RadioMode::Tx { .. });
+                            radio.lock(|r| set_radio_to_standby(r, radio_state))?;
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                            tx_queue.fail_all(uart_tx_queue); // Whatever was queued is off, on air or not
+                            if aborted_tx {
+                                if let Some(overrides) = tx_restore.take() {
+                                    let _ = radio.lock(|r| overrides.restore(r, active_cfg));
+                                }
                             }
-                        };
-                    }
-                    UartPacketType::RadioFreqConfig => {
-                        let config = match RadioFreqConfigurator::try_from(packet) {
-                            Ok(cfg) => cfg,
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                        }
+                        UartPacketType::RadioGoSleep => {
+                            let cmd = RadioSleepCommand::try_from(packet)?;
+                            radio.lock(|r| cmd.configure_radio(r, radio_state))?;
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                            tx_queue.fail_all(uart_tx_queue);
+                            if !cmd.is_warm() {
+                                *tx_restore = None; // Cold sleep wipes the overrides anyway
+                            }
+                        }
+                        UartPacketType::RadioRecvStart => {
+                            let cmd = RadioRxCommand::try_from(packet)?;
+                            if !active_cfg.can_receive() {
+                                defmt::error!("Got RadioRecvStart while on BPSK");
+                                return Err(Error::Rejected);
                             }
-                        };
 
-                        match radio.lock(|r| config.configure_radio(r)) {
-                            Ok(_) => {
-                                UartPacketEncoder::make_ack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                            radio.lock(|r| cmd.configure_radio(r, radio_state, pkt_params.as_ref()))?;
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::GetRadioPhyConfig
+                        | UartPacketType::GetRadioFreqConfig
+                        | UartPacketType::GetRadioLoraConfig
+                        | UartPacketType::GetRadioGfskConfig
+                        | UartPacketType::GetRadioBpskConfig => {
+                            if !active_cfg.encode_readback(packet.get_type(), uart_tx_queue) {
+                                return Err(Error::Rejected);
                             }
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                        }
+                        UartPacketType::RadioPhyConfig => {
+                            let config = RadioPhyConfigurator::try_from(packet)?;
+                            radio_state.check_configure()?;
+                            radio.lock(|r| config.configure_radio(r))?;
+                            radio_state.set(RadioMode::Standby);
+                            active_cfg.set_phy(config);
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::RadioFreqConfig => {
+                            let config = RadioFreqConfigurator::try_from(packet)?;
+                            radio_state.check_configure()?;
+                            radio.lock(|r| config.configure_radio(r))?;
+                            active_cfg.set_freq(config);
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::RadioLoraConfig => {
+                            let config = RadioLoraConfigurator::try_from(packet)?;
+                            radio_state.check_configure()?;
+                            radio.lock(|r| config.configure_radio(r))?;
+                            radio_state.set(RadioMode::Standby);
+                            *pkt_params = Some(config.packet_params());
+                            active_cfg.set_modulation(ActiveModulation::LoRa(config));
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::RadioGfskConfig => {
+                            let config = RadioGfskConfigurator::try_from(packet)?;
+                            radio_state.check_configure()?;
+                            radio.lock(|r| config.configure_radio(r))?;
+                            radio_state.set(RadioMode::Standby);
+                            *pkt_params = Some(config.packet_params());
+                            active_cfg.set_modulation(ActiveModulation::Gfsk(config));
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::RadioBpskConfig => {
+                            let config = RadioBpskConfigurator::try_from(packet)?;
+                            radio_state.check_configure()?;
+                            radio.lock(|r| config.configure_radio(r))?;
+                            radio_state.set(RadioMode::Standby);
+                            *pkt_params = Some(config.packet_params());
+                            active_cfg.set_modulation(ActiveModulation::Bpsk(config));
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::RangeTestStart => {
+                            let cmd = RangeTestCommand::try_from(packet)?;
+                            // Both sides wait for a reply, which BPSK can't receive
+                            if radio_state.is_busy() || !active_cfg.can_receive() {
+                                return Err(Error::Rejected);
                             }
-                        };
-                    }
-                    UartPacketType::RadioLoraConfig => {
-                        let config = match RadioLoraConfigurator::try_from(packet) {
-                            Ok(cfg) => cfg,
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+
+                            cmd.apply(range_test);
+
+                            let ret = match cmd.role() {
+                                RangeTestRole::Initiator => {
+                                    let mut ping_buf: [u8; RANGE_TEST_ECHO_LEN] = [0; RANGE_TEST_ECHO_LEN];
+                                    match range_test.next_ping(crate::now_ms(), &mut ping_buf) {
+                                        Some(ping_len) => {
+                                            (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_tx);
+                                            radio.lock(|r| {
+                                                start_radio_tx(
+                                                    r,
+                                                    radio_state,
+                                                    pkt_params.as_ref(),
+                                                    &ping_buf[0..ping_len],
+                                                    5000,
+                                                )
+                                            })
+                                        }
+                                        None => Ok(()), // Zero pings requested, nothing to do
+                                    }
+                                }
+                                RangeTestRole::Responder => {
+                                    (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_rx);
+                                    radio.lock(|r| start_radio_rx(r, radio_state, pkt_params.as_ref(), 0))
+                                }
+                            };
+                            if let Err(err) = ret {
+                                range_test.stop();
+                                return Err(Error::from(err));
                             }
-                        };
 
-                        match radio.lock(|r| config.configure_radio(r)) {
-                            Ok(_) => {
-                                UartPacketEncoder::make_ack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::RangeTestStop => {
+                            range_test.stop();
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::SettingsSave => {
+                            let cmd = SettingsSaveCommand::try_from(packet)?;
+                            if cmd.auto_rx_ms().is_some() && !active_cfg.can_receive() {
+                                defmt::error!("Got SettingsSave with auto Rx while on BPSK");
+                                return Err(Error::Rejected);
                             }
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+
+                            // Keep the saved baud rate, that one only changes through SetBaudRate
+                            let mut record = SettingsRecord::new(active_cfg, cmd.auto_rx_ms());
+                            record.set_baud(load_settings().and_then(|prev| prev.baud()));
+                            save_settings(record)?;
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::SettingsLoad => {
+                            let record = match load_settings() {
+                                Some(record) => record,
+                                None => {
+                                    defmt::warn!("Got SettingsLoad while nothing saved");
+                                    return Err(Error::Rejected);
+                                }
+                            };
+
+                            radio_state.check_configure()?;
+                            radio.lock(|r| record.apply(r, active_cfg, pkt_params))?;
+                            radio_state.set(RadioMode::Standby);
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::SettingsErase => {
+                            erase_settings()?;
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::FactoryReset => {
+                            radio_state.check_configure()?;
+                            erase_settings()?;
+                            radio.lock(|r| apply_factory_defaults(r, active_cfg, pkt_params))?;
+                            radio_state.set(RadioMode::Standby);
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::SetBaudRate => {
+                            let cmd = SetBaudRateCommand::try_from(packet)?;
+
+                            // Ack goes out at the old rate, the switch happens once the Tx queue drained
+                            baud.request(cmd.baud(), cmd.persist());
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::GetStats => {
+                            stats::encode_stats(uart_tx_queue);
+                        }
+                        UartPacketType::GetStatus => {
+                            let cmd = GetStatusCommand::try_from(packet)?;
+
+                            let now = crate::now_ms();
+                            if let Some(interval_ms) = cmd.interval_ms() {
+                                status.set_interval(interval_ms, now);
                             }
-                        };
+                            status.encode(now, radio_state, active_cfg, uart_rx_queue, uart_tx_queue);
+                        }
+                        UartPacketType::ResetStats => {
+                            stats::reset_stats();
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::SetRxForwarding => {
+                            let cmd = SetRxForwardingCommand::try_from(packet)?;
+
+                            // Whatever piled up meanwhile goes out with the next Tx run
+                            rx_queue.set_paused(!cmd.enabled());
+                            UartPacketEncoder::make_ack(uart_tx_queue, credits);
+                        }
+                        UartPacketType::Restart => {
+                            cortex_m::peripheral::SCB::sys_reset();
+                        }
+                        UartPacketType::EnterSleepStop2 => {
+                            enter_stop2_mode();
+                        }
+                        _ => return Err(Error::Rejected),
                     }
-                    UartPacketType::RadioGfskConfig => {
-                        let config = match RadioGfskConfigurator::try_from(packet) {
-                            Ok(cfg) => cfg,
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
-                            }
-                        };
 
-                        match radio.lock(|r| config.configure_radio(r)) {
-                            Ok(_) => {
-                                UartPacketEncoder::make_ack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
-                            }
-                            Err(_) => {
-                                UartPacketEncoder::make_nack(uart_tx_queue);
-                                rtic::pend(Interrupt::LPUART1);
-                                return;
+                    Ok(())
+                };
+
+                if let Err(err) = handle_packet() {
+                    UartPacketEncoder::make_nack(uart_tx_queue);
+
+                    // Malformed or refused commands only get the NACK, anything else gets reported and recovered from
+                    if !matches!(err, Error::Packet(_) | Error::Rejected) {
+                        radio.lock(|r| recover(err, r, radio_state, radio_health, tx_queue, tx_restore, uart_tx_queue));
+                    }
+                }
+                rtic::pend(Interrupt::LPUART1);
+            }
+        }
+    }
+
+    #[task(binds = RADIO_IRQ_BUSY, shared = [uart_tx_q, radio, rf_sw_1, rf_sw_2, radio_state, radio_health, range_test, pkt_params, active_cfg, tx_restore, tx_queue, rx_queue])]
+    fn radio_task(ctx: radio_task::Context) {
+        let mut radio = ctx.shared.radio;
+        let mut rf_sw_1 = ctx.shared.rf_sw_1;
+        let mut rf_sw_2 = ctx.shared.rf_sw_2;
+        let uart_tx_queue = ctx.shared.uart_tx_q;
+        let radio_state = ctx.shared.radio_state;
+        let radio_health = ctx.shared.radio_health;
+        let range_test = ctx.shared.range_test;
+        let pkt_params = ctx.shared.pkt_params;
+        let active_cfg = ctx.shared.active_cfg;
+        let tx_restore = ctx.shared.tx_restore;
+        let tx_queue = ctx.shared.tx_queue;
+        let rx_queue = ctx.shared.rx_queue;
+
+        let mut handle_irq = || -> Result<(), Error> {
+            let irq = radio.lock(|r| -> Result<u16, Error> {
+                let (_, irq) = r.irq_status()?;
+                r.clear_irq_status(irq)?;
+                Ok(irq)
+            })?;
+
+            let event = radio_state.handle_irq(irq);
+            defmt::info!("radio: {:?}", event);
+            stats::count_radio_event(&event);
+            if matches!(event, RadioEvent::TxDone | RadioEvent::RxDone) {
+                radio_health.record_ok();
+            }
+
+            // Transmission with per-packet overrides is over, put the configured settings back before Rx
+            if matches!(event, RadioEvent::TxDone | RadioEvent::TxTimeout) {
+                if event == RadioEvent::TxTimeout {
+                    defmt::error!("radio: TxTimeout! Something fucked?");
+                    let op_error = radio.lock(read_radio_op_error)?;
+                    if radio_health.record_op_error(op_error) {
+                        rtic::pend(Interrupt::TIM17);
+                    }
+                    encode_radio_fault(RadioFaultKind::TxTimeout, irq, op_error, uart_tx_queue);
+                    rtic::pend(Interrupt::LPUART1);
+                }
+
+                if let Some(overrides) = tx_restore.take() {
+                    radio.lock(|r| overrides.restore(r, active_cfg))?;
+                }
+
+                let status = match event {
+                    RadioEvent::TxDone => TxStatus::Done,
+                    _ => TxStatus::Timeout,
+                };
+                if tx_queue.finish(status, uart_tx_queue) {
+                    rtic::pend(Interrupt::LPUART1);
+                }
+
+                if tx_queue.has_pending() {
+                    (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_tx);
+                    if radio.lock(|r| tx_queue.start_next(r, radio_state, pkt_params.as_ref(), uart_tx_queue)) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            // Range test ping/echo buffer, big enough for both
+            let mut range_buf: [u8; RANGE_TEST_ECHO_LEN] = [0; RANGE_TEST_ECHO_LEN];
+
+            match event {
+                RadioEvent::TxTimeout | RadioEvent::RxTimeout => {
+                    if let Some(record) = range_test.handle_timeout() {
+                        defmt::warn!("radio: range test lost {:?}", record);
+                        record.encode(uart_tx_queue);
+                        rtic::pend(Interrupt::LPUART1);
+
+                        if let Some(ping_len) = range_test.next_ping(crate::now_ms(), &mut range_buf) {
+                            (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_tx);
+                            radio.lock(|r| {
+                                start_radio_tx(r, radio_state, pkt_params.as_ref(), &range_buf[0..ping_len], 5000)
+                            })?;
+                            return Ok(());
+                        }
+                    }
+                }
+                RadioEvent::RxDone => {
+                    let mut rx_buf: [u8; 256] = [0; 256];
+                    let (rx_len, pkt_status) = radio.lock(|r| read_radio_packet(r, &mut rx_buf))?;
+                    radio_state.record_rx(
+                        pkt_status.signal_rssi_pkt().to_integer(),
+                        pkt_status.snr_pkt().to_integer(),
+                    );
+
+                    let now = crate::now_ms();
+                    match range_test.handle_rx(&rx_buf[0..rx_len], &pkt_status, now, &mut range_buf) {
+                        RangeTestRx::NotRangeTest => {
+                            rx_queue.push(&rx_buf[0..rx_len], pkt_status);
+                            rx_queue.flush_into(uart_tx_queue);
+                        }
+                        RangeTestRx::Ignored => {}
+                        RangeTestRx::SendEcho(echo_len) => {
+                            (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_tx);
+                            radio.lock(|r| {
+                                start_radio_tx(r, radio_state, pkt_params.as_ref(), &range_buf[0..echo_len], 5000)
+                            })?;
+                            return Ok(());
+                        }
+                        RangeTestRx::Record(record) => {
+                            defmt::info!("radio: range test got {:?}", record);
+                            record.encode(uart_tx_queue);
+                            rtic::pend(Interrupt::LPUART1);
+
+                            if let Some(ping_len) = range_test.next_ping(now, &mut range_buf) {
+                                (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_tx);
+                                radio.lock(|r| {
+                                    start_radio_tx(r, radio_state, pkt_params.as_ref(), &range_buf[0..ping_len], 5000)
+                                })?;
+                                return Ok(());
                             }
-                        };
+                        }
                     }
-                    UartPacketType::Restart => {
-                        cortex_m::peripheral::SCB::sys_reset();
+
+                    rtic::pend(Interrupt::LPUART1); // Let UART to send off the stuff received too
+                }
+                RadioEvent::RxError { header, crc } => {
+                    let kind = if header {
+                        RadioFaultKind::HeaderError
+                    } else {
+                        RadioFaultKind::CrcError
+                    };
+                    defmt::warn!("radio: Rx failed, header={}, crc={}", header, crc);
+                    let op_error = radio.lock(read_radio_op_error)?;
+                    if radio_health.record_op_error(op_error) {
+                        rtic::pend(Interrupt::TIM17);
                     }
-                    UartPacketType::EnterSleepStop2 => {
-                        enter_stop2_mode();
+                    encode_radio_fault(kind, irq, op_error, uart_tx_queue);
+                    rtic::pend(Interrupt::LPUART1);
+                }
+                RadioEvent::RxDetected { preamble, sync } => {
+                    // Still receiving, RxDone (or an error) follows
+                    defmt::debug!("radio: Rx in progress, preamble={}, sync={}", preamble, sync);
+                    return Ok(());
+                }
+                RadioEvent::TxDone => {
+                    range_test.handle_tx_done();
+                }
+                RadioEvent::CadDone { detected } => {
+                    defmt::info!("radio: CAD done, detected={}", detected);
+                }
+                RadioEvent::Unexpected(irq) => {
+                    // Nothing in IRQ reading?? Maybe this is a manual triggered one?
+                    defmt::warn!(
+                        "SubGhz IRQ 0x{:x} triggered while nothing needed in {:?}?",
+                        irq,
+                        radio_state.mode()
+                    );
+                    let op_error = radio.lock(read_radio_op_error)?;
+                    if radio_health.record_op_error(op_error) {
+                        rtic::pend(Interrupt::TIM17);
                     }
-                    _ => {
-                        UartPacketEncoder::make_nack(uart_tx_queue);
+                    if op_error != 0 {
+                        encode_radio_fault(RadioFaultKind::DeviceError, irq, op_error, uart_tx_queue);
                         rtic::pend(Interrupt::LPUART1);
                     }
+                    return Ok(());
                 }
             }
-        } else if isr.tc().bit_is_set() {
-            defmt::trace!("uart_task: LPUART_ISR TC set!?");
-            match uart_tx_queue.dequeue() {
-                Some(b) => uart.write(b).unwrap(),
-                None => {
-                    dp.LPUART.icr.write(|w| w.tccf().set_bit());
-                    dp.LPUART.cr1.modify(|_, w| w.tcie().clear_bit());
-                    defmt::trace!("uart_task: nothing left in Tx queue, TC cleared!");
-                    return;
-                }
+
+            // Continuous Rx is still going, only re-arm once the radio really left Rx
+            if matches!(radio_state.mode(), RadioMode::Rx { .. }) {
+                return Ok(());
             }
-        } else {
-            // Software triggered?
-            defmt::info!("uart_task: Software-triggered Tx!");
-            dp.LPUART.cr1.modify(|_, w| w.tcie().set_bit());
-            match uart_tx_queue.dequeue() {
-                Some(b) => uart.write(b).unwrap(),
-                None => {
-                    dp.LPUART.icr.write(|w| w.tccf().set_bit());
-                    dp.LPUART.cr1.modify(|_, w| w.tcie().clear_bit());
-                    defmt::trace!("uart_task: nothing left in Tx queue, TC cleared!");
-                    return;
-                }
+
+            // BPSK can't receive, so wait in standby for the next Tx instead
+            if !active_cfg.can_receive() {
+                radio.lock(|r| set_radio_to_standby(r, radio_state))?;
+                return Ok(());
             }
+
+            // ...and then go back to Rx
+            (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_rx);
+            let rx_timeout = range_test.rx_timeout().unwrap_or(5000);
+            radio.lock(|r| start_radio_rx(r, radio_state, pkt_params.as_ref(), rx_timeout))?;
+            Ok(())
+        };
+
+        if let Err(err) = handle_irq() {
+            radio.lock(|r| recover(err, r, radio_state, radio_health, tx_queue, tx_restore, uart_tx_queue));
+            range_test.stop();
+            rtic::pend(Interrupt::LPUART1);
         }
     }
 
-    #[task(binds = RADIO_IRQ_BUSY, local = [was_tx: bool = false], shared = [uart_tx_q, radio, rf_sw_1, rf_sw_2])]
-    fn radio_task(ctx: radio_task::Context) {
+    /// Rx ring is half or completely full, have `uart_task` catch up before the DMA laps it
+    #[task(binds = DMA1_CHANNEL1)]
+    fn uart_rx_dma_task(_: uart_rx_dma_task::Context) {
+        UartDma::rx_clear_irq();
+        rtic::pend(Interrupt::LPUART1);
+    }
+
+    /// Wakes `uart_task` after a baud rate change, so it can fall back even if nothing arrives at all
+    #[task(priority = 1)]
+    async fn uart_baud_fallback(_: uart_baud_fallback::Context) {
+        crate::Mono::delay(BAUD_FALLBACK_MS.millis()).await;
+        rtic::pend(Interrupt::LPUART1);
+    }
+
+    /// Wakes `uart_task` to check on a frame in progress, it spawns this again while the frame is still going
+    #[task(priority = 1)]
+    async fn uart_frame_timeout(_: uart_frame_timeout::Context) {
+        crate::Mono::delay(FRAME_TIMEOUT_MS.millis()).await;
+        rtic::pend(Interrupt::LPUART1);
+    }
+
+    /// Wakes `uart_task` so the periodic `FlowStatus` (and `Status`, if on) goes out even when the link is quiet
+    #[task(priority = 1)]
+    async fn flow_status_tick(_: flow_status_tick::Context) {
+        loop {
+            crate::Mono::delay(FLOW_STATUS_INTERVAL_MS.millis()).await;
+            rtic::pend(Interrupt::LPUART1);
+        }
+    }
+
+    /// Feeds the IWDG as long as `uart_task`, the radio and the idle loop all checked in since the last round.
+    /// Runs at the lowest priority, so anything hogging the CPU starves it too.
+    #[task(priority = 1)]
+    async fn watchdog_tick(_: watchdog_tick::Context) {
+        loop {
+            crate::Mono::delay(WATCHDOG_CHECK_MS.millis()).await;
+            feed_watchdog();
+        }
+    }
+
+    /// Nudges `radio_health_task` every second so a radio that stopped talking gets noticed
+    #[task(priority = 1)]
+    async fn radio_health_tick(_: radio_health_tick::Context) {
+        loop {
+            crate::Mono::delay(1000.millis()).await;
+            rtic::pend(Interrupt::TIM17);
+        }
+    }
+
+    // TIM17 isn't used, its interrupt is borrowed to run the radio checks with the other radio resources
+    #[task(binds = TIM17, shared = [uart_tx_q, radio, rf_sw_1, rf_sw_2, radio_state, radio_health, range_test, pkt_params, active_cfg, tx_restore, tx_queue])]
+    fn radio_health_task(ctx: radio_health_task::Context) {
         let mut radio = ctx.shared.radio;
+        let uart_tx_queue = ctx.shared.uart_tx_q;
+        let radio_state = ctx.shared.radio_state;
+        let radio_health = ctx.shared.radio_health;
+        let pkt_params = ctx.shared.pkt_params;
+        let active_cfg = ctx.shared.active_cfg;
+        let tx_queue = ctx.shared.tx_queue;
 
-        let was_tx = ctx.local.was_tx;
-        let irq = radio.lock(|r| {
-            let (_, irq) = r.irq_status().unwrap();
-            r.clear_irq_status(irq).unwrap();
-            irq
-        });
+        let now = crate::now_ms();
+        let cause = radio_health
+            .take_request()
+            .or_else(|| radio_health.check_busy_stuck(radio_state, now))
+            .or_else(|| radio_health.check_irq_overdue(radio_state, now));
 
-        if irq & Irq::Timeout.mask() != 0 {
-            if *was_tx {
-                defmt::error!("radio: TxTimeout! Something fucked?");
-            } else {
-                defmt::info!("radio: RxTimeout! Re-enter Rx");
-                (ctx.shared.rf_sw_1, ctx.shared.rf_sw_2).lock(|sw1, sw2| {
-                    sw1.set_level_high();
-                    sw2.set_level_low();
-                });
+        let cause = match cause {
+            Some(cause) => cause,
+            None => {
+                radio_alive();
+                return;
+            }
+        };
 
-                radio.lock(|r| start_radio_rx(r, 5000).unwrap());
+        if !radio_health.begin_recovery() {
+            if radio_health.mark_gave_up() {
+                defmt::error!("radio_health_task: radio keeps failing, giving up");
+                encode_recovery_event(cause, RecoveryResult::GaveUp, radio_health.recoveries(), uart_tx_queue);
+                rtic::pend(Interrupt::LPUART1);
+            }
+            return;
+        }
+
+        let prev_mode = radio_state.mode();
+        defmt::warn!("radio_health_task: recovering from {:?}, was {:?}", cause, prev_mode);
+
+        // Overrides are gone with the reset, the replayed configuration is what's active now
+        *ctx.shared.tx_restore = None;
+        ctx.shared.range_test.stop();
+
+        let result = match radio.lock(|r| recover_radio(r, radio_state, active_cfg)) {
+            Ok(_) => RecoveryResult::Recovered,
+            Err(err) => {
+                defmt::error!("radio_health_task: recovery failed: {:?}", err);
+                RecoveryResult::Failed
+            }
+        };
+
+        encode_recovery_event(cause, result, radio_health.recoveries(), uart_tx_queue);
+        rtic::pend(Interrupt::LPUART1);
+        if result != RecoveryResult::Recovered {
+            tx_queue.fail_all(uart_tx_queue);
+            return;
+        }
+
+        let (mut rf_sw_1, mut rf_sw_2) = (ctx.shared.rf_sw_1, ctx.shared.rf_sw_2);
+
+        // Resume where we were: queued packets get sent again, anything else on air is lost
+        if matches!(prev_mode, RadioMode::Tx { .. }) {
+            (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_tx);
+            if radio.lock(|r| tx_queue.retry(r, radio_state, pkt_params.as_ref())) {
+                return;
+            }
+            tx_queue.finish(TxStatus::Failed, uart_tx_queue);
+        }
+
+        let rx_timeout = match prev_mode {
+            RadioMode::Rx { timeout_ms } => timeout_ms,
+            RadioMode::Tx { .. } if active_cfg.can_receive() => 5000, // Tx goes back to Rx afterwards, unless on BPSK
+            _ => return,
+        };
+
+        (&mut rf_sw_1, &mut rf_sw_2).lock(rf_switch_rx);
+        if let Err(err) = radio.lock(|r| start_radio_rx(r, radio_state, pkt_params.as_ref(), rx_timeout)) {
+            defmt::error!("radio_health_task: failed to resume Rx: {:?}", err);
+        }
+    }
+
+    /// Report the error to the host, and reset the radio if SPI to it failed.
+    /// Framer errors are handled by `resync_framer` since they need the UART Rx queue.
+    fn recover(
+        err: Error,
+        radio: &mut SubGhz<SgMiso, SgMosi>,
+        radio_state: &mut RadioState,
+        radio_health: &mut RadioHealth,
+        tx_queue: &mut TxQueue,
+        tx_restore: &mut Option<TxOverrides>,
+        uart_tx_queue: &mut CacheQueue,
+    ) {
+        defmt::error!("recover: {:?}, doing {:?}", err, err.recovery());
+        err.encode(uart_tx_queue);
+
+        if err.recovery() == Recovery::ResetRadio {
+            *tx_restore = None; // Gone together with whatever was on air
+            tx_queue.fail_all(uart_tx_queue);
+            if let Err(reset_err) = reset_radio(radio, radio_state) {
+                defmt::error!("recover: radio still not responding: {:?}", reset_err);
+                radio_health.request(RecoveryCause::SpiErrors);
+                rtic::pend(Interrupt::TIM17);
+            } else if radio_health.record_spi_error() {
+                rtic::pend(Interrupt::TIM17);
             }
-        } else if irq & Irq::RxDone.mask() != 0 {
-            defmt::info!("radio: RxDone, handling...");
-            let uart_tx_queue = ctx.shared.uart_tx_q;
-            radio.lock(|r| handle_radio_rx_done(r, irq, uart_tx_queue).unwrap());
-
-            // ...and then go back to Rx?
-            (ctx.shared.rf_sw_1, ctx.shared.rf_sw_2).lock(|sw1, sw2| {
-                sw1.set_level_high();
-                sw2.set_level_low();
-            });
-            radio.lock(|r| start_radio_rx(r, 5000).unwrap());
-            rtic::pend(Interrupt::LPUART1); // Let UART to send off the stuff received too
-        } else if irq & Irq::TxDone.mask() != 0 {
-            defmt::info!("radio: TxDone, re-enter Rx");
-            (ctx.shared.rf_sw_1, ctx.shared.rf_sw_2).lock(|sw1, sw2| {
-                sw1.set_level_high();
-                sw2.set_level_low();
-            });
-            radio.lock(|r| start_radio_rx(r, 5000).unwrap());
-        } else {
-            // Nothing in IRQ reading?? Maybe this is a manual triggered one?
-            defmt::warn!("SubGhz IRQ triggered while nothing needed?");
         }
     }
 
+    /// Drop the half received frame and start over from the next SLIP_START
+    fn resync_framer(err: Error, baud: &mut BaudState, uart_rx_queue: &mut CacheQueue, uart_tx_queue: &mut CacheQueue) {
+        defmt::error!("resync_framer: {:?}", err);
+        while uart_rx_queue.dequeue().is_some() {}
+
+        // Garbled bytes mean the host is probably talking at another rate, no point reporting at this one.
+        // A frame cut short or too long says nothing about the rate, so those don't move it.
+        let wrong_rate = matches!(
+            err,
+            Error::Uart(uart::Error::Framing | uart::Error::Noise) | Error::Packet(_)
+        );
+        if wrong_rate && baud.hunt(crate::now_ms()) {
+            return;
+        }
+
+        err.encode(uart_tx_queue);
+        rtic::pend(Interrupt::LPUART1);
+    }
+
     // Optional idle, can be removed if not needed.
     #[idle]
     fn idle(_: idle::Context) -> ! {
         loop {
             //enter_stop2_mode();
+            idle_alive();
             wfi();
             continue;
         }
@@ -1,8 +1,5 @@
 use heapless::spsc::Queue;
-use stm32wlxx_hal::{
-    gpio,
-    subghz::{SleepCfg, Startup},
-};
+use stm32wlxx_hal::gpio;
 
 pub const RFSW_GPIO_OUTPUT_ARGS: gpio::OutputArgs = gpio::OutputArgs {
     level: gpio::PinState::Low,
@@ -18,4 +15,20 @@ pub const SLIP_ESC_END: u8 = 0xdc;
 pub const SLIP_ESC_ESC: u8 = 0xdd;
 pub const SLIP_ESC_START: u8 = 0xde;
 pub type CacheQueue = Queue<u8, 1024>;
-pub const SLEEP_CFG: SleepCfg = SleepCfg::new().set_rtc_wakeup_en(false).set_startup(Startup::Cold);
+
+// Factory defaults in the same wire format as the config packets: EU868 868.1MHz, LoRa SF9/BW125/CR4-5, 14dBm on LP PA
+pub const DEFAULT_PHY_CFG: [u8; 6] = [0x04, 0x00, 0x00, 0x0e, 0x04, 0x00]; // Duty 4, HpMax 0, LP PA, 14dBm, 200us ramp, no Rx boost
+pub const DEFAULT_FREQ_CFG: [u8; 4] = 868_100_000u32.to_le_bytes();
+pub const DEFAULT_LORA_CFG: [u8; 13] = [
+    0x08, 0x00, // 8 symbols preamble
+    0x01, // Variable length header
+    0xff, // Max payload length
+    0x01, // CRC on
+    0x00, // Tx IQ normal
+    0x09, // SF9
+    0x04, // BW125
+    0x01, // CR4/5
+    0x00, // No LDRO
+    0x14, 0x24, // Private sync word
+    0x00, // Rx IQ normal
+];
@@ -0,0 +1,106 @@
+use stm32wlxx_hal::{flash, subghz, uart};
+
+use crate::{
+    constants::CacheQueue,
+    packet::{uart_pkt_encoder::UartPacketEncoder, UartPacketError, UartPacketType},
+    radio::RadioError,
+    radio_state::IllegalTransition,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum Error {
+    Packet(UartPacketError),
+    Radio(RadioError),
+    Uart(uart::Error),
+    UartRxOverflow, // Host sent more than a frame can hold without a SLIP_END
+    RadioBusy,      // BUSY never went low, even after a reset
+    Flash(flash::Error),
+    UartFrameTimeout, // Frame stopped halfway, see `FrameTimer`
+    Rejected,         // Command can't be carried out right now (radio busy, queue full...), the NACK says it all
+    WatchdogOption,   // IWDG_STOP option byte couldn't be changed, see `apply_watchdog_option`
+}
+
+/// What the interrupt handler should do about an error instead of panicking
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum Recovery {
+    /// SPI to the radio failed, put the radio back to a known state
+    ResetRadio = 0,
+    /// Throw away the partially received UART frame and wait for the next SLIP_START
+    ResyncFramer = 1,
+    /// Nothing is broken, just let the host know
+    ReportOnly = 2,
+}
+
+impl From<UartPacketError> for Error {
+    fn from(value: UartPacketError) -> Self {
+        Error::Packet(value)
+    }
+}
+
+impl From<RadioError> for Error {
+    fn from(value: RadioError) -> Self {
+        Error::Radio(value)
+    }
+}
+
+impl From<IllegalTransition> for Error {
+    fn from(value: IllegalTransition) -> Self {
+        Error::Radio(RadioError::from(value))
+    }
+}
+
+impl From<subghz::Error> for Error {
+    fn from(value: subghz::Error) -> Self {
+        Error::Radio(RadioError::Spi(value))
+    }
+}
+
+impl From<uart::Error> for Error {
+    fn from(value: uart::Error) -> Self {
+        Error::Uart(value)
+    }
+}
+
+impl From<flash::Error> for Error {
+    fn from(value: flash::Error) -> Self {
+        Error::Flash(value)
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> u8 {
+        match self {
+            Error::Packet(_) => 0x01,
+            Error::Radio(RadioError::Spi(_)) => 0x02,
+            Error::Radio(RadioError::IllegalTransition { .. }) => 0x03,
+            Error::Uart(_) => 0x04,
+            Error::UartRxOverflow => 0x05,
+            Error::RadioBusy => 0x06,
+            Error::Flash(_) => 0x07,
+            Error::UartFrameTimeout => 0x08,
+            Error::Rejected => 0x09,
+            Error::WatchdogOption => 0x0a,
+        }
+    }
+
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Error::Radio(RadioError::Spi(_)) | Error::RadioBusy => Recovery::ResetRadio,
+            Error::Radio(RadioError::IllegalTransition { .. })
+            | Error::Flash(_)
+            | Error::Rejected
+            | Error::WatchdogOption => Recovery::ReportOnly,
+            Error::Packet(_) | Error::Uart(_) | Error::UartRxOverflow | Error::UartFrameTimeout => {
+                Recovery::ResyncFramer
+            }
+        }
+    }
+
+    /// 1 byte of error code, then 1 byte of the recovery taken
+    pub fn encode(&self, queue: &mut CacheQueue) {
+        let mut encoder = UartPacketEncoder::new(UartPacketType::ErrorReport, queue);
+        encoder.add_payload(&[self.code(), self.recovery() as u8]);
+        encoder.finalize();
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{
+    constants::CacheQueue,
+    packet::{uart_pkt_encoder::UartPacketEncoder, UartPacketType},
+    rx_queue::RxPacketQueue,
+};
+
+/// Credits also go out on their own this often, so a host waiting for room doesn't need to poll with `Ping`
+pub const FLOW_STATUS_INTERVAL_MS: u32 = 1000;
+
+/// Keeps track of when the last `FlowStatus` went out
+pub struct FlowStatus {
+    sent_at_ms: Option<u32>,
+}
+
+impl Default for FlowStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlowStatus {
+    pub const fn new() -> FlowStatus {
+        FlowStatus { sent_at_ms: None }
+    }
+
+    /// 2 bytes of Rx credits, 2 bytes of UART Tx queue fill, 1 byte of radio packets waiting and 1 byte of forwarding paused
+    fn encode(&mut self, now_ms: u32, credits: u16, rx_queue: &RxPacketQueue, uart_tx_q: &mut CacheQueue) {
+        let mut payload: [u8; 6] = [0; 6];
+        payload[0..2].copy_from_slice(&credits.to_le_bytes());
+        payload[2..4].copy_from_slice(&(uart_tx_q.len() as u16).to_le_bytes());
+        payload[4] = rx_queue.len() as u8;
+        payload[5] = rx_queue.is_paused() as u8;
+
+        let mut encoder = UartPacketEncoder::new(UartPacketType::FlowStatus, uart_tx_q);
+        encoder.add_payload(&payload);
+        encoder.finalize();
+        self.sent_at_ms = Some(now_ms);
+    }
+
+    /// Send one if nothing went out for `FLOW_STATUS_INTERVAL_MS`, returns true if it did
+    pub fn encode_if_due(
+        &mut self,
+        now_ms: u32,
+        credits: u16,
+        rx_queue: &RxPacketQueue,
+        uart_tx_q: &mut CacheQueue,
+    ) -> bool {
+        if let Some(sent_at_ms) = self.sent_at_ms {
+            if now_ms.wrapping_sub(sent_at_ms) < FLOW_STATUS_INTERVAL_MS {
+                return false;
+            }
+        }
+
+        self.encode(now_ms, credits, rx_queue, uart_tx_q);
+        true
+    }
+}
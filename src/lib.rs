@@ -1,17 +1,36 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)] // Host unit tests (see `radio_state`) get std and a main
+#[cfg(not(test))]
 use defmt_rtt as _; // global logger
 
+#[cfg(not(test))]
 use panic_probe as _;
+#[cfg(not(test))]
 use stm32wlxx_hal as _; // memory layout
 
 pub mod constants;
+pub mod error;
+pub mod flow_status;
 pub mod packet;
 pub mod power;
 pub mod radio;
+pub mod radio_cfg;
+pub mod radio_recovery;
+pub mod radio_state;
+pub mod range_test;
+pub mod rx_queue;
+pub mod settings;
+pub mod stats;
+pub mod status;
+pub mod tx_queue;
+pub mod uart_baud;
+pub mod uart_dma;
+pub mod uart_framer;
+pub mod watchdog;
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(not(test))]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
@@ -1,15 +1,30 @@
 use crc::Table;
 
-use crate::constants::{CacheQueue, SLIP_END, SLIP_ESC, SLIP_ESC_END, SLIP_ESC_ESC, SLIP_ESC_START, SLIP_START};
+use crate::{
+    constants::{CacheQueue, SLIP_END, SLIP_ESC, SLIP_ESC_END, SLIP_ESC_ESC, SLIP_ESC_START, SLIP_START},
+    stats::{self, Stat},
+};
 
+pub mod radio_bpsk_cfg;
 pub mod radio_freq_cfg;
 pub mod radio_gfsk_cfg;
 pub mod radio_lora_cfg;
 pub mod radio_phy_cfg;
+pub mod radio_queue_send;
 pub mod radio_rx_cmd;
+pub mod radio_send_ex;
+pub mod radio_sleep_cmd;
+pub mod range_test_cmd;
+pub mod rx_forward_cmd;
+pub mod settings_cmd;
+pub mod status_cmd;
+pub mod uart_baud_cmd;
 pub mod uart_pkt_decoder;
 pub mod uart_pkt_encoder;
 
+/// Bumped whenever a frame layout changes in a way old hosts can't cope with
+pub const PROTOCOL_VERSION: u8 = 1;
+
 pub const CRC: crc::Crc<u16, Table<1>> = crc::Crc::<u16, Table<1>>::new(&crc::CRC_16_KERMIT);
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
@@ -29,11 +44,30 @@ pub enum UartPacketType {
     RadioFreqConfig = 0x11,
     RadioLoraConfig = 0x12,
     RadioGfskConfig = 0x13,
+    RadioBpskConfig = 0x14,
+    GetRadioPhyConfig = 0x30,
+    GetRadioFreqConfig = 0x31,
+    GetRadioLoraConfig = 0x32,
+    GetRadioGfskConfig = 0x33,
+    GetRadioBpskConfig = 0x34,
+    GetStats = 0x35,
+    ResetStats = 0x36,
+    GetStatus = 0x37,
     EnterSleepStop2 = 0x20, // Enter STOP2; TBD
+    SetBaudRate = 0x21,
+    SetRxForwarding = 0x22,
     RadioGoSleep = 0x40,
     RadioGoIdle = 0x41,
     RadioSend = 0x42,
     RadioRecvStart = 0x43,
+    RadioSendEx = 0x44,
+    RadioQueueSend = 0x45,
+    RangeTestStart = 0x50,
+    RangeTestStop = 0x51,
+    SettingsSave = 0x60,
+    SettingsLoad = 0x61,
+    SettingsErase = 0x62,
+    FactoryReset = 0x63,
     Restart = 0x7f,
 
     // Reply from module
@@ -41,6 +75,16 @@ pub enum UartPacketType {
     Ack = 0x83,
     Nack = 0x84,
     RadioReceivedPacket = 0xC1,
+    RangeTestRecord = 0xC2,
+    RadioTxStatus = 0xC3,
+    RadioRxDropped = 0xC4,
+    RadioFault = 0xC5,
+    ErrorReport = 0xC6,
+    RadioRecovery = 0xC7,
+    FlowStatus = 0xC8,
+    Stats = 0xC9,
+    Status = 0xCA,
+    Boot = 0xCB,
 }
 
 impl TryFrom<u8> for UartPacketType {
@@ -53,23 +97,52 @@ impl TryFrom<u8> for UartPacketType {
             0x11 => Ok(Self::RadioFreqConfig),
             0x12 => Ok(Self::RadioLoraConfig),
             0x13 => Ok(Self::RadioGfskConfig),
+            0x14 => Ok(Self::RadioBpskConfig),
             0x20 => Ok(Self::EnterSleepStop2),
+            0x21 => Ok(Self::SetBaudRate),
+            0x22 => Ok(Self::SetRxForwarding),
+            0x30 => Ok(Self::GetRadioPhyConfig),
+            0x31 => Ok(Self::GetRadioFreqConfig),
+            0x32 => Ok(Self::GetRadioLoraConfig),
+            0x33 => Ok(Self::GetRadioGfskConfig),
+            0x34 => Ok(Self::GetRadioBpskConfig),
+            0x35 => Ok(Self::GetStats),
+            0x36 => Ok(Self::ResetStats),
+            0x37 => Ok(Self::GetStatus),
             0x40 => Ok(Self::RadioGoSleep),
             0x41 => Ok(Self::RadioGoIdle),
             0x42 => Ok(Self::RadioSend),
             0x43 => Ok(Self::RadioRecvStart),
+            0x44 => Ok(Self::RadioSendEx),
+            0x45 => Ok(Self::RadioQueueSend),
+            0x50 => Ok(Self::RangeTestStart),
+            0x51 => Ok(Self::RangeTestStop),
+            0x60 => Ok(Self::SettingsSave),
+            0x61 => Ok(Self::SettingsLoad),
+            0x62 => Ok(Self::SettingsErase),
+            0x63 => Ok(Self::FactoryReset),
             0x7f => Ok(Self::Restart),
             _ => Err(UartPacketError::UnknownPacketError),
         }
     }
 }
 
+/// Worst case of a SLIP encoded frame: start & end, then every byte escaped
+pub(crate) const fn max_encoded_len(payload_len: usize) -> usize {
+    2 + (1 + 2 + payload_len + 2) * 2
+}
+
+pub(crate) fn free_space(queue: &CacheQueue) -> usize {
+    queue.capacity() - queue.len()
+}
+
 fn enqueue_ditch_oldest(queue: &mut CacheQueue, b: u8) {
     match queue.enqueue(b) {
         Ok(_) => {}
         Err(b) => {
             queue.dequeue(); // Drop the oldest
             queue.enqueue(b).unwrap();
+            stats::count(Stat::UartTxBytesDropped);
         }
     }
 }
@@ -0,0 +1,74 @@
+use stm32wlxx_hal::{
+    spi::{SgMiso, SgMosi},
+    subghz::{BpskModParams, BpskPacketParams, Error, FskBitrate, PacketType, StandbyClk, SubGhz},
+};
+
+use crate::radio::PacketParams;
+
+use super::{uart_pkt_decoder::UartPacketDecoder, UartPacketError};
+
+const BPSK_CFG_LEN: usize = 3;
+
+pub struct RadioBpskConfigurator {
+    bitrate: u16,
+    bpsk_mod: BpskModParams,
+    pkt_params: BpskPacketParams,
+    raw: [u8; BPSK_CFG_LEN],
+}
+
+impl TryFrom<UartPacketDecoder> for RadioBpskConfigurator {
+    type Error = UartPacketError;
+
+    fn try_from(value: UartPacketDecoder) -> Result<Self, Self::Error> {
+        let (buf, len) = value.get_payload();
+
+        // 2 bytes of bitrate, then 1 byte of payload length
+        if len < 3 {
+            defmt::error!("RadioBpskConfigurator: require 3 bytes while got {} bytes", len);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        // The SubGHz BPSK modulator only does 100bps and 600bps
+        let bitrate = u16::from_le_bytes(buf[0..=1].try_into().unwrap());
+        if bitrate != 100 && bitrate != 600 {
+            defmt::error!("RadioBpskConfigurator: invalid bitrate: {}", bitrate);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        let bpsk_mod = BpskModParams::new().set_bitrate(FskBitrate::from_bps(bitrate as u32));
+        let pkt_params = BpskPacketParams::new().set_payload_len(buf[2]);
+
+        defmt::info!(
+            "RadioBpskCfg decode: bitrate={:?}bps, payload_len={:?}",
+            bitrate,
+            buf[2]
+        );
+        Ok(RadioBpskConfigurator {
+            bitrate,
+            bpsk_mod,
+            pkt_params,
+            raw: buf[0..BPSK_CFG_LEN].try_into().unwrap(),
+        })
+    }
+}
+
+impl RadioBpskConfigurator {
+    pub fn configure_radio(&self, radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), Error> {
+        radio.set_standby(StandbyClk::Rc)?;
+        radio.set_packet_type(PacketType::Bpsk)?;
+        radio.set_bpsk_mod_params(&self.bpsk_mod)?;
+        radio.set_bpsk_packet_params(&self.pkt_params)?;
+
+        defmt::info!("RadioBpskConfigurator: BPSK config OK, bitrate={}bps", self.bitrate);
+        Ok(())
+    }
+
+    pub fn packet_params(&self) -> PacketParams {
+        PacketParams::Bpsk(self.pkt_params)
+    }
+
+    /// Settings in the same format `RadioBpskConfig` takes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+}
@@ -7,6 +7,7 @@ use super::{uart_pkt_decoder::UartPacketDecoder, UartPacketError};
 
 pub struct RadioFreqConfigurator {
     freq_hz: u32,
+    raw: [u8; 4],
 }
 
 impl TryFrom<UartPacketDecoder> for RadioFreqConfigurator {
@@ -21,7 +22,7 @@ impl TryFrom<UartPacketDecoder> for RadioFreqConfigurator {
         }
 
         let freq_hz = u32::from_le_bytes(buf[0..=3].try_into().unwrap());
-        if freq_hz < (100 * 1000000) || freq_hz > (960 * 1000000) {
+        if !freq_in_range(freq_hz) {
             defmt::error!(
                 "RadioFreqConfigurator: frequency out of range! freq_hz={}; 0x{:x} 0x{:x} 0x{:x} 0x{:x}",
                 freq_hz,
@@ -33,19 +34,41 @@ impl TryFrom<UartPacketDecoder> for RadioFreqConfigurator {
             return Err(UartPacketError::CorruptedError);
         }
 
-        Ok(RadioFreqConfigurator { freq_hz })
+        Ok(RadioFreqConfigurator {
+            freq_hz,
+            raw: freq_hz.to_le_bytes(),
+        })
     }
 }
 
+pub(crate) fn freq_in_range(freq_hz: u32) -> bool {
+    ((100 * 1000000)..=(960 * 1000000)).contains(&freq_hz)
+}
+
+pub fn set_radio_frequency(radio: &mut SubGhz<SgMiso, SgMosi>, freq_hz: u32) -> Result<(), subghz::Error> {
+    radio.set_rf_frequency(&RfFreq::from_frequency(freq_hz))?;
+
+    let mhz = freq_hz / 1000000;
+    let freqx4 = mhz - (mhz % 4);
+    radio.calibrate_image(CalibrateImage::from_freq((freqx4 - 4) as u16, (freqx4 + 4) as u16))?;
+
+    Ok(())
+}
+
 impl RadioFreqConfigurator {
     pub fn configure_radio(&self, radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), subghz::Error> {
-        radio.set_rf_frequency(&RfFreq::from_frequency(self.freq_hz))?;
-
-        let mhz = self.freq_hz / 1000000;
-        let freqx4 = mhz - (mhz % 4);
-        radio.calibrate_image(CalibrateImage::from_freq((freqx4 - 4) as u16, (freqx4 + 4) as u16))?;
+        set_radio_frequency(radio, self.freq_hz)?;
 
         defmt::info!("RadioFreqConfigurator: config OK, freq={:?}", self.freq_hz);
         Ok(())
     }
+
+    pub fn freq_hz(&self) -> u32 {
+        self.freq_hz
+    }
+
+    /// Settings in the same format `RadioFreqConfig` takes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
 }
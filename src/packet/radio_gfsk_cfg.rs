@@ -6,12 +6,44 @@ use stm32wlxx_hal::{
     },
 };
 
+use crate::radio::{set_radio_gfsk_addr, PacketParams};
+
 use super::{uart_pkt_decoder::UartPacketDecoder, UartPacketError};
 
+const GFSK_BASE_CFG_LEN: u16 = 9 + 10 + 8;
+const GFSK_ENGINE_CFG_LEN: u16 = 8;
+
+pub(crate) fn bitrate_in_range(bitrate: u32) -> bool {
+    (600..=300_000).contains(&bitrate)
+}
+
+// Optional packet engine settings for talking to other vendors' GFSK radios
+struct GfskPacketEngine {
+    whitening_seed: u16,
+    crc_poly: u16,
+    crc_init: u16,
+    node_addr: u8,
+    broadcast_addr: u8,
+}
+
+// SX126x reset values, what a config without the engine block gets so nothing is left over from the last one
+const GFSK_ENGINE_DEFAULT: GfskPacketEngine = GfskPacketEngine {
+    whitening_seed: 0x0100,
+    crc_poly: 0x1021,
+    crc_init: 0x1d0f,
+    node_addr: 0,
+    broadcast_addr: 0,
+};
+
 pub struct RadioGfskConfigurator {
     pkt_params: GenericPacketParams,
     fsk_mod: FskModParams,
+    pulse_shape: FskPulseShape,
+    bandwidth: FskBandwidth,
+    fdev: u32,
     sync_word: [u8; 8],
+    engine: Option<GfskPacketEngine>,
+    raw: [u8; (GFSK_BASE_CFG_LEN + GFSK_ENGINE_CFG_LEN) as usize],
 }
 
 impl TryFrom<UartPacketDecoder> for RadioGfskConfigurator {
@@ -22,8 +54,9 @@ impl TryFrom<UartPacketDecoder> for RadioGfskConfigurator {
 
         // First 9 bytes are FSK Packet parameters,
         // then next 10 bytes are FSK modulation parameters
-        // then finally 8 bytes of sync word
-        if len < (9 + 10 + 8) {
+        // then 8 bytes of sync word,
+        // then optionally 8 more bytes of packet engine settings
+        if len < GFSK_BASE_CFG_LEN {
             defmt::error!("RadioGfskConfigurator: invalid packet length = {}", len);
             return Err(UartPacketError::CorruptedError);
         }
@@ -86,6 +119,11 @@ impl TryFrom<UartPacketDecoder> for RadioGfskConfigurator {
 
         // Then the 10th bytes to 19th bytes starts from here for FSK modulation paramaters
         let bitrate: u32 = u32::from_le_bytes(buf[9..=12].try_into().unwrap());
+        if !bitrate_in_range(bitrate) {
+            defmt::error!("RadioGfskConfigurator: bitrate out of range: {}", bitrate);
+            return Err(UartPacketError::CorruptedError);
+        }
+
         let pulse_shape: FskPulseShape = match buf[13] {
             0 => FskPulseShape::None,
             0x08 => FskPulseShape::Bt03,
@@ -115,27 +153,96 @@ impl TryFrom<UartPacketDecoder> for RadioGfskConfigurator {
             .set_fdev(FskFdev::from_hertz(fdev));
 
         let sync_word: [u8; 8] = buf[19..=26].try_into().unwrap();
+
+        // Whitening seed (9 bits), CRC polynomial, CRC initial value, then node & broadcast address
+        let engine = if len >= GFSK_BASE_CFG_LEN + GFSK_ENGINE_CFG_LEN {
+            let whitening_seed = u16::from_le_bytes(buf[27..=28].try_into().unwrap());
+            if whitening_seed > 0x1ff {
+                defmt::error!("RadioGfskConfigurator: invalid whitening seed: 0x{:x}", whitening_seed);
+                return Err(UartPacketError::CorruptedError);
+            }
+
+            Some(GfskPacketEngine {
+                whitening_seed,
+                crc_poly: u16::from_le_bytes(buf[29..=30].try_into().unwrap()),
+                crc_init: u16::from_le_bytes(buf[31..=32].try_into().unwrap()),
+                node_addr: buf[33],
+                broadcast_addr: buf[34],
+            })
+        } else {
+            None
+        };
+
+        // Engine settings not sent mean the defaults, so they only show up in the readback if they were given
+        let mut raw = [0; (GFSK_BASE_CFG_LEN + GFSK_ENGINE_CFG_LEN) as usize];
+        let raw_len = if engine.is_some() {
+            GFSK_BASE_CFG_LEN + GFSK_ENGINE_CFG_LEN
+        } else {
+            GFSK_BASE_CFG_LEN
+        } as usize;
+        raw[0..raw_len].copy_from_slice(&buf[0..raw_len]);
+
         return Ok(RadioGfskConfigurator {
             pkt_params,
             fsk_mod,
+            pulse_shape,
+            bandwidth,
+            fdev,
             sync_word,
+            engine,
+            raw,
         });
     }
 }
 
 impl RadioGfskConfigurator {
     pub fn configure_radio(&self, radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), Error> {
-        // Do we need to reset...?
-        // let dp = unsafe { Peripherals::steal() };
-        // dp.RCC.csr.modify(|_, w| w.rfrst().set_bit());
-        // dp.RCC.csr.modify(|_, w| w.rfrst().clear_bit());
-
         radio.set_standby(StandbyClk::Rc)?;
         radio.set_packet_type(PacketType::Fsk)?;
         radio.set_sync_word(&self.sync_word)?;
         radio.set_fsk_mod_params(&self.fsk_mod)?;
         radio.set_packet_params(&self.pkt_params)?;
 
+        // Always written, or a replay of a config without them would keep whatever came before
+        let engine = self.engine.as_ref().unwrap_or(&GFSK_ENGINE_DEFAULT);
+        radio.set_whitening_seed(engine.whitening_seed)?;
+        radio.set_crc_polynomial(engine.crc_poly)?;
+        radio.set_initial_crc_value(engine.crc_init)?;
+        set_radio_gfsk_addr(radio, engine.node_addr, engine.broadcast_addr)?;
+
+        defmt::info!(
+            "RadioGfskConfigurator: whitening=0x{:x}, CRC poly=0x{:x} init=0x{:x}, node=0x{:x} broadcast=0x{:x}",
+            engine.whitening_seed,
+            engine.crc_poly,
+            engine.crc_init,
+            engine.node_addr,
+            engine.broadcast_addr
+        );
+
         Ok(())
     }
+
+    /// Settings in the same format `RadioGfskConfig` takes
+    pub fn as_bytes(&self) -> &[u8] {
+        match self.engine {
+            Some(_) => &self.raw,
+            None => &self.raw[0..(GFSK_BASE_CFG_LEN as usize)],
+        }
+    }
+
+    pub fn packet_params(&self) -> PacketParams {
+        PacketParams::Gfsk(self.pkt_params)
+    }
+
+    pub fn fsk_mod(&self) -> &FskModParams {
+        &self.fsk_mod
+    }
+
+    pub fn fsk_mod_with_bitrate(&self, bitrate: u32) -> FskModParams {
+        FskModParams::new()
+            .set_bitrate(FskBitrate::from_bps(bitrate))
+            .set_pulse_shape(self.pulse_shape)
+            .set_bandwidth(self.bandwidth)
+            .set_fdev(FskFdev::from_hertz(self.fdev))
+    }
 }
@@ -6,12 +6,60 @@ use stm32wlxx_hal::{
     },
 };
 
+use crate::radio::{LoRaIqConfig, PacketParams};
+
 use super::{uart_pkt_decoder::UartPacketDecoder, UartPacketError};
 
+const LORA_CFG_LEN: usize = 13;
+
 pub struct RadioLoraConfigurator {
     pkt_params: LoRaPacketParams,
     lora_mod: LoRaModParams,
     sync_word: [u8; 2],
+    tx_invert_iq: bool,
+    rx_invert_iq: bool,
+    raw: [u8; LORA_CFG_LEN],
+}
+
+pub(crate) fn parse_sf(val: u8) -> Option<SpreadingFactor> {
+    match val {
+        0x05 => Some(SpreadingFactor::Sf5),
+        0x06 => Some(SpreadingFactor::Sf6),
+        0x07 => Some(SpreadingFactor::Sf7),
+        0x08 => Some(SpreadingFactor::Sf8),
+        0x09 => Some(SpreadingFactor::Sf9),
+        0x0A => Some(SpreadingFactor::Sf10),
+        0x0B => Some(SpreadingFactor::Sf11),
+        0x0C => Some(SpreadingFactor::Sf12),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_bw(val: u8) -> Option<LoRaBandwidth> {
+    match val {
+        0x00 => Some(LoRaBandwidth::Bw7),
+        0x08 => Some(LoRaBandwidth::Bw10),
+        0x01 => Some(LoRaBandwidth::Bw15),
+        0x09 => Some(LoRaBandwidth::Bw20),
+        0x02 => Some(LoRaBandwidth::Bw31),
+        0x0A => Some(LoRaBandwidth::Bw41),
+        0x03 => Some(LoRaBandwidth::Bw62),
+        0x04 => Some(LoRaBandwidth::Bw125),
+        0x05 => Some(LoRaBandwidth::Bw250),
+        0x06 => Some(LoRaBandwidth::Bw500),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_cr(val: u8) -> Option<CodingRate> {
+    match val {
+        0x00 => Some(CodingRate::Cr44),
+        0x01 => Some(CodingRate::Cr45),
+        0x02 => Some(CodingRate::Cr46),
+        0x03 => Some(CodingRate::Cr47),
+        0x04 => Some(CodingRate::Cr48),
+        _ => None,
+    }
 }
 
 impl TryFrom<UartPacketDecoder> for RadioLoraConfigurator {
@@ -32,52 +80,37 @@ impl TryFrom<UartPacketDecoder> for RadioLoraConfigurator {
             HeaderType::Variable
         };
 
+        // 6th byte is Tx IQ inversion, the optional 13th byte is Rx IQ inversion.
+        // Older hosts only send the 6th byte, which then applies to both directions.
+        let tx_invert_iq = buf[5] != 0;
+        let rx_invert_iq = if len >= 13 { buf[12] != 0 } else { tx_invert_iq };
+
         let pkt_params = LoRaPacketParams::new()
             .set_preamble_len(preamble_len)
             .set_header_type(header_type)
             .set_payload_len(buf[3])
             .set_crc_en(buf[4] != 0)
-            .set_invert_iq(buf[5] != 0);
-
-        let sf: SpreadingFactor = match buf[6] {
-            0x05 => SpreadingFactor::Sf5,
-            0x06 => SpreadingFactor::Sf6,
-            0x07 => SpreadingFactor::Sf7,
-            0x08 => SpreadingFactor::Sf8,
-            0x09 => SpreadingFactor::Sf9,
-            0x0A => SpreadingFactor::Sf10,
-            0x0B => SpreadingFactor::Sf11,
-            0x0C => SpreadingFactor::Sf12,
-            _ => {
+            .set_invert_iq(rx_invert_iq);
+
+        let sf: SpreadingFactor = match parse_sf(buf[6]) {
+            Some(sf) => sf,
+            None => {
                 defmt::error!("RadioLoraConfigurator: invalid SF: 0x{:x}", buf[6]);
                 return Err(UartPacketError::CorruptedError);
             }
         };
 
-        let bw: LoRaBandwidth = match buf[7] {
-            0x00 => LoRaBandwidth::Bw7,
-            0x08 => LoRaBandwidth::Bw10,
-            0x01 => LoRaBandwidth::Bw15,
-            0x09 => LoRaBandwidth::Bw20,
-            0x02 => LoRaBandwidth::Bw31,
-            0x0A => LoRaBandwidth::Bw41,
-            0x03 => LoRaBandwidth::Bw62,
-            0x04 => LoRaBandwidth::Bw125,
-            0x05 => LoRaBandwidth::Bw250,
-            0x06 => LoRaBandwidth::Bw500,
-            _ => {
+        let bw: LoRaBandwidth = match parse_bw(buf[7]) {
+            Some(bw) => bw,
+            None => {
                 defmt::error!("RadioLoraConfigurator: invalid BW: 0x{:x}", buf[7]);
                 return Err(UartPacketError::CorruptedError);
             }
         };
 
-        let cr: CodingRate = match buf[8] {
-            0x00 => CodingRate::Cr44,
-            0x01 => CodingRate::Cr45,
-            0x02 => CodingRate::Cr46,
-            0x03 => CodingRate::Cr47,
-            0x04 => CodingRate::Cr48,
-            _ => {
+        let cr: CodingRate = match parse_cr(buf[8]) {
+            Some(cr) => cr,
+            None => {
                 defmt::error!("RadioLoraConfigurator: invalid CR: 0x{:x}", buf[8]);
                 return Err(UartPacketError::CorruptedError);
             }
@@ -91,12 +124,13 @@ impl TryFrom<UartPacketDecoder> for RadioLoraConfigurator {
         let sync_word: [u8; 2] = buf[10..=11].try_into().unwrap();
 
         defmt::info!(
-            "RadioLoraCfg decode: preamble_len={:?}, header_type={:?}, payload_len={:?}, crc={:?} inverted_iq={:?}",
+            "RadioLoraCfg decode: preamble_len={:?}, header_type={:?}, payload_len={:?}, crc={:?} inverted_iq: tx={:?} rx={:?}",
             preamble_len,
             header_type,
             buf[3],
             buf[4] != 0,
-            buf[5] != 0
+            tx_invert_iq,
+            rx_invert_iq
         );
         defmt::info!(
             "RadioLoraCfg decode: SF={:?} BW={:?} CR={:?} LDRO={:?}; SyncWord={:?}",
@@ -106,10 +140,18 @@ impl TryFrom<UartPacketDecoder> for RadioLoraConfigurator {
             buf[9] != 0,
             sync_word
         );
+        // Rx IQ byte is optional on the wire, always fill it in so the readback says what got applied
+        let mut raw: [u8; LORA_CFG_LEN] = [0; LORA_CFG_LEN];
+        raw[0..12].copy_from_slice(&buf[0..12]);
+        raw[12] = rx_invert_iq as u8;
+
         Ok(RadioLoraConfigurator {
             lora_mod,
             pkt_params,
             sync_word,
+            tx_invert_iq,
+            rx_invert_iq,
+            raw,
         })
     }
 }
@@ -130,4 +172,17 @@ impl RadioLoraConfigurator {
         );
         Ok(())
     }
+
+    /// Settings in the same format `RadioLoraConfig` takes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn lora_mod(&self) -> &LoRaModParams {
+        &self.lora_mod
+    }
+
+    pub fn packet_params(&self) -> PacketParams {
+        PacketParams::LoRa(LoRaIqConfig::new(self.pkt_params, self.tx_invert_iq, self.rx_invert_iq))
+    }
 }
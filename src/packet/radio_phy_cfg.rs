@@ -5,11 +5,24 @@ use stm32wlxx_hal::{
 
 use super::{uart_pkt_decoder::UartPacketDecoder, UartPacketError};
 
+const PHY_CFG_LEN: usize = 6;
+
+// Tx power in dBm as the radio takes it (two's complement), the LP PA only goes up to 15dBm
+pub(crate) fn power_in_range(hp_pa: bool, power: u8) -> bool {
+    if hp_pa {
+        (-9..=22).contains(&(power as i8))
+    } else {
+        (-17..=15).contains(&(power as i8))
+    }
+}
+
 pub struct RadioPhyConfigurator {
     tx_params: TxParams,
+    ramp_time: RampTime,
     pa_config: PaConfig,
     ocp: Ocp,
     rx_boost: bool,
+    raw: [u8; PHY_CFG_LEN],
 }
 
 impl TryFrom<UartPacketDecoder> for RadioPhyConfigurator {
@@ -48,6 +61,14 @@ impl TryFrom<UartPacketDecoder> for RadioPhyConfigurator {
             }
         };
 
+        if !power_in_range(buf[2] != 0, buf[3]) {
+            defmt::error!(
+                "RadioPhyConfigurator: Tx power out of range for the PA: {}",
+                buf[3] as i8
+            );
+            return Err(UartPacketError::CorruptedError);
+        }
+
         let rx_boost = buf[5] != 0;
         defmt::info!(
             "RadioPhyCfg decode: PaDutyCycle={:?}; HpMax={:?}; PaSel={:?}; RampTime={:?}; Power={:?}dB; RxBoost={:?}",
@@ -63,9 +84,11 @@ impl TryFrom<UartPacketDecoder> for RadioPhyConfigurator {
 
         Ok(RadioPhyConfigurator {
             tx_params,
+            ramp_time,
             pa_config,
             ocp,
             rx_boost,
+            raw: buf[0..PHY_CFG_LEN].try_into().unwrap(),
         })
     }
 }
@@ -93,4 +116,22 @@ impl RadioPhyConfigurator {
         );
         Ok(())
     }
+
+    /// Settings in the same format `RadioPhyConfig` takes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn tx_params(&self) -> &TxParams {
+        &self.tx_params
+    }
+
+    /// Whether a per-packet power override fits the configured PA
+    pub fn power_in_range(&self, power: u8) -> bool {
+        power_in_range(self.raw[2] != 0, power)
+    }
+
+    pub fn tx_params_with_power(&self, power: u8) -> TxParams {
+        TxParams::new().set_ramp_time(self.ramp_time).set_power(power)
+    }
 }
@@ -0,0 +1,35 @@
+use super::{uart_pkt_decoder::UartPacketDecoder, UartPacketError};
+
+pub struct RadioQueueSendCommand {
+    id: u16,
+    packet: UartPacketDecoder,
+}
+
+impl TryFrom<UartPacketDecoder> for RadioQueueSendCommand {
+    type Error = UartPacketError;
+
+    fn try_from(value: UartPacketDecoder) -> Result<Self, Self::Error> {
+        let (buf, len) = value.get_payload();
+
+        // 2 bytes of packet ID picked by the host, then the payload to send
+        if len <= 2 || len > (2 + 255) {
+            defmt::error!("RadioQueueSendCommand: invalid length: {} bytes", len);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        let id = u16::from_le_bytes(buf[0..=1].try_into().unwrap());
+
+        Ok(RadioQueueSendCommand { id, packet: value })
+    }
+}
+
+impl RadioQueueSendCommand {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        let (buf, len) = self.packet.get_payload();
+        &buf[2..(len as usize)]
+    }
+}
@@ -1,9 +1,13 @@
 use stm32wlxx_hal::{
     spi::{SgMiso, SgMosi},
-    subghz::{self, SubGhz},
+    subghz::SubGhz,
 };
 
-use crate::{packet::UartPacketError, radio::start_radio_rx};
+use crate::{
+    packet::UartPacketError,
+    radio::{start_radio_rx, PacketParams, RadioError},
+    radio_state::RadioState,
+};
 
 use super::uart_pkt_decoder::UartPacketDecoder;
 
@@ -29,9 +33,14 @@ impl TryFrom<UartPacketDecoder> for RadioRxCommand {
 }
 
 impl RadioRxCommand {
-    pub fn configure_radio(&self, radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), subghz::Error> {
+    pub fn configure_radio(
+        &self,
+        radio: &mut SubGhz<SgMiso, SgMosi>,
+        state: &mut RadioState,
+        pkt_params: Option<&PacketParams>,
+    ) -> Result<(), RadioError> {
         defmt::info!("RadioRxCommand: trigger Rx start, timeout={}ms", self.timeout_ms);
-        start_radio_rx(radio, self.timeout_ms)?;
+        start_radio_rx(radio, state, pkt_params, self.timeout_ms)?;
         Ok(())
     }
 }
@@ -0,0 +1,187 @@
+use stm32wlxx_hal::{
+    spi::{SgMiso, SgMosi},
+    subghz::{self, LoRaModParams, StandbyClk, SubGhz},
+};
+
+use crate::radio_cfg::ActiveRadioConfig;
+
+use super::{
+    radio_freq_cfg::{freq_in_range, set_radio_frequency},
+    radio_gfsk_cfg::bitrate_in_range,
+    radio_lora_cfg::{parse_bw, parse_cr, parse_sf},
+    uart_pkt_decoder::UartPacketDecoder,
+    UartPacketError,
+};
+
+const OVERRIDE_FREQ: u8 = 1 << 0;
+const OVERRIDE_POWER: u8 = 1 << 1;
+const OVERRIDE_LORA_MOD: u8 = 1 << 2;
+const OVERRIDE_GFSK_BITRATE: u8 = 1 << 3;
+const SEND_EX_HEADER_LEN: u16 = 14;
+
+/// Radio settings changed for one transmission only, put back by `restore` after TxDone
+#[derive(Clone, Copy)]
+pub struct TxOverrides {
+    flags: u8,
+    freq_hz: u32,
+    power: u8,
+    lora_mod: LoRaModParams,
+    gfsk_bitrate: u32,
+}
+
+impl TxOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.flags == 0
+    }
+
+    /// Whatever we override has to be configured beforehand, otherwise there's nothing to restore to
+    pub fn can_restore(&self, active: &ActiveRadioConfig) -> bool {
+        (self.flags & OVERRIDE_FREQ == 0 || active.freq().is_some())
+            && (self.flags & OVERRIDE_POWER == 0 || active.phy().is_some())
+            && (self.flags & OVERRIDE_LORA_MOD == 0 || active.lora().is_some())
+            && (self.flags & OVERRIDE_GFSK_BITRATE == 0 || active.gfsk().is_some())
+    }
+
+    /// Power override has to fit the PA that's configured, which only shows up once the PHY config is known
+    pub fn power_in_range(&self, active: &ActiveRadioConfig) -> bool {
+        self.flags & OVERRIDE_POWER == 0 || active.phy().is_some_and(|phy| phy.power_in_range(self.power))
+    }
+
+    pub fn apply(&self, radio: &mut SubGhz<SgMiso, SgMosi>, active: &ActiveRadioConfig) -> Result<(), subghz::Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        radio.set_standby(StandbyClk::Rc)?;
+        if self.flags & OVERRIDE_FREQ != 0 {
+            set_radio_frequency(radio, self.freq_hz)?;
+        }
+
+        if let (true, Some(phy)) = (self.flags & OVERRIDE_POWER != 0, active.phy()) {
+            radio.set_tx_params(&phy.tx_params_with_power(self.power))?;
+        }
+
+        if self.flags & OVERRIDE_LORA_MOD != 0 {
+            radio.set_lora_mod_params(&self.lora_mod)?;
+        }
+
+        if let (true, Some(gfsk)) = (self.flags & OVERRIDE_GFSK_BITRATE != 0, active.gfsk()) {
+            radio.set_fsk_mod_params(&gfsk.fsk_mod_with_bitrate(self.gfsk_bitrate))?;
+        }
+
+        defmt::info!("TxOverrides: applied, flags=0x{:x}", self.flags);
+        Ok(())
+    }
+
+    pub fn restore(&self, radio: &mut SubGhz<SgMiso, SgMosi>, active: &ActiveRadioConfig) -> Result<(), subghz::Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        radio.set_standby(StandbyClk::Rc)?;
+        if let (true, Some(freq)) = (self.flags & OVERRIDE_FREQ != 0, active.freq()) {
+            freq.configure_radio(radio)?;
+        }
+
+        if let (true, Some(phy)) = (self.flags & OVERRIDE_POWER != 0, active.phy()) {
+            radio.set_tx_params(phy.tx_params())?;
+        }
+
+        if let (true, Some(lora)) = (self.flags & OVERRIDE_LORA_MOD != 0, active.lora()) {
+            radio.set_lora_mod_params(lora.lora_mod())?;
+        }
+
+        if let (true, Some(gfsk)) = (self.flags & OVERRIDE_GFSK_BITRATE != 0, active.gfsk()) {
+            radio.set_fsk_mod_params(gfsk.fsk_mod())?;
+        }
+
+        defmt::info!("TxOverrides: restored, flags=0x{:x}", self.flags);
+        Ok(())
+    }
+}
+
+pub struct RadioSendExCommand {
+    overrides: TxOverrides,
+    packet: UartPacketDecoder,
+}
+
+impl TryFrom<UartPacketDecoder> for RadioSendExCommand {
+    type Error = UartPacketError;
+
+    fn try_from(value: UartPacketDecoder) -> Result<Self, Self::Error> {
+        let (buf, len) = value.get_payload();
+
+        // 1 byte of override flags, 4 bytes of frequency, 1 byte of Tx power,
+        // 4 bytes of LoRa SF/BW/CR/LDRO, 4 bytes of GFSK bitrate, then the payload to send.
+        // Fields without their flag set are ignored but must still be present.
+        if len <= SEND_EX_HEADER_LEN {
+            defmt::error!("RadioSendExCommand: require more than 14 bytes while got {} bytes", len);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        if len - SEND_EX_HEADER_LEN > 255 {
+            defmt::error!(
+                "RadioSendExCommand: payload too long: {} bytes",
+                len - SEND_EX_HEADER_LEN
+            );
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        let flags = buf[0];
+        let freq_hz = u32::from_le_bytes(buf[1..=4].try_into().unwrap());
+        if flags & OVERRIDE_FREQ != 0 && !freq_in_range(freq_hz) {
+            defmt::error!("RadioSendExCommand: frequency out of range! freq_hz={}", freq_hz);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        let power = buf[5];
+        let mut lora_mod = LoRaModParams::new();
+        if flags & OVERRIDE_LORA_MOD != 0 {
+            let (sf, bw, cr) = match (parse_sf(buf[6]), parse_bw(buf[7]), parse_cr(buf[8])) {
+                (Some(sf), Some(bw), Some(cr)) => (sf, bw, cr),
+                _ => {
+                    defmt::error!(
+                        "RadioSendExCommand: invalid SF/BW/CR: 0x{:x} 0x{:x} 0x{:x}",
+                        buf[6],
+                        buf[7],
+                        buf[8]
+                    );
+                    return Err(UartPacketError::CorruptedError);
+                }
+            };
+
+            lora_mod = lora_mod.set_sf(sf).set_bw(bw).set_cr(cr).set_ldro_en(buf[9] != 0);
+        }
+
+        let gfsk_bitrate = u32::from_le_bytes(buf[10..=13].try_into().unwrap());
+        if flags & OVERRIDE_GFSK_BITRATE != 0 && !bitrate_in_range(gfsk_bitrate) {
+            defmt::error!(
+                "RadioSendExCommand: GFSK bitrate out of range! bitrate={}",
+                gfsk_bitrate
+            );
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        Ok(RadioSendExCommand {
+            overrides: TxOverrides {
+                flags,
+                freq_hz,
+                power,
+                lora_mod,
+                gfsk_bitrate,
+            },
+            packet: value,
+        })
+    }
+}
+
+impl RadioSendExCommand {
+    pub fn overrides(&self) -> TxOverrides {
+        self.overrides
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        let (buf, len) = self.packet.get_payload();
+        &buf[(SEND_EX_HEADER_LEN as usize)..(len as usize)]
+    }
+}
@@ -0,0 +1,81 @@
+use stm32wlxx_hal::{
+    spi::{SgMiso, SgMosi},
+    subghz::{SleepCfg, Startup, SubGhz},
+};
+
+use crate::{
+    packet::UartPacketError,
+    radio::{set_radio_rtc_wakeup, set_radio_to_sleep, RadioError},
+    radio_state::RadioState,
+};
+
+use super::uart_pkt_decoder::UartPacketDecoder;
+
+/// Payload is optional, an empty `RadioGoSleep` keeps the old behaviour (cold start, no RTC wakeup)
+pub struct RadioSleepCommand {
+    warm: bool,
+    rtc_wakeup_ms: u32,
+}
+
+impl TryFrom<UartPacketDecoder> for RadioSleepCommand {
+    type Error = UartPacketError;
+
+    fn try_from(value: UartPacketDecoder) -> Result<Self, Self::Error> {
+        let (buf, len) = value.get_payload();
+
+        let warm = match len {
+            0 => false,
+            _ => match buf[0] {
+                0 => false,
+                1 => true,
+                _ => {
+                    defmt::error!("RadioSleepCommand: invalid startup mode {}", buf[0]);
+                    return Err(UartPacketError::CorruptedError);
+                }
+            },
+        };
+
+        let rtc_wakeup_ms = match len {
+            0 | 1 => 0,
+            5.. => u32::from_le_bytes(buf[1..=4].try_into().unwrap()),
+            _ => {
+                defmt::error!(
+                    "RadioSleepCommand: require 5 bytes for RTC wakeup while got {} bytes",
+                    len
+                );
+                return Err(UartPacketError::CorruptedError);
+            }
+        };
+
+        Ok(RadioSleepCommand { warm, rtc_wakeup_ms })
+    }
+}
+
+impl RadioSleepCommand {
+    pub fn is_warm(&self) -> bool {
+        self.warm
+    }
+
+    pub fn configure_radio(
+        &self,
+        radio: &mut SubGhz<SgMiso, SgMosi>,
+        state: &mut RadioState,
+    ) -> Result<(), RadioError> {
+        defmt::info!(
+            "RadioSleepCommand: go sleep, warm={}, rtc_wakeup={}ms",
+            self.warm,
+            self.rtc_wakeup_ms
+        );
+
+        let cfg = SleepCfg::new()
+            .set_rtc_wakeup_en(self.rtc_wakeup_ms != 0)
+            .set_startup(if self.warm { Startup::Warm } else { Startup::Cold });
+
+        if self.rtc_wakeup_ms != 0 {
+            set_radio_rtc_wakeup(radio, self.rtc_wakeup_ms)?;
+        }
+
+        set_radio_to_sleep(radio, state, cfg, self.warm)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use crate::{
+    packet::UartPacketError,
+    range_test::{RangeTest, RangeTestRole},
+};
+
+use super::uart_pkt_decoder::UartPacketDecoder;
+
+pub struct RangeTestCommand {
+    role: RangeTestRole,
+    count: u16,
+    echo_timeout_ms: u32,
+}
+
+impl TryFrom<UartPacketDecoder> for RangeTestCommand {
+    type Error = UartPacketError;
+
+    fn try_from(value: UartPacketDecoder) -> Result<Self, Self::Error> {
+        let (buf, len) = value.get_payload();
+
+        // 1 byte of role, 2 bytes of ping count, then 4 bytes of echo timeout
+        if len < 7 {
+            defmt::error!("RangeTestCommand: require 7 bytes while got {} bytes", len);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        let role = match buf[0] {
+            0 => RangeTestRole::Initiator,
+            1 => RangeTestRole::Responder,
+            _ => {
+                defmt::error!("RangeTestCommand: invalid role: {}", buf[0]);
+                return Err(UartPacketError::CorruptedError);
+            }
+        };
+
+        let count = u16::from_le_bytes(buf[1..=2].try_into().unwrap());
+        let echo_timeout_ms = u32::from_le_bytes(buf[3..=6].try_into().unwrap());
+        if role == RangeTestRole::Initiator && (echo_timeout_ms == 0 || echo_timeout_ms == u32::MAX) {
+            defmt::error!("RangeTestCommand: initiator needs a finite echo timeout");
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        Ok(RangeTestCommand {
+            role,
+            count,
+            echo_timeout_ms,
+        })
+    }
+}
+
+impl RangeTestCommand {
+    pub fn role(&self) -> RangeTestRole {
+        self.role
+    }
+
+    pub fn apply(&self, range_test: &mut RangeTest) {
+        range_test.start(self.role, self.count, self.echo_timeout_ms);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::packet::UartPacketError;
+
+use super::uart_pkt_decoder::UartPacketDecoder;
+
+/// 1 byte: 0 to pause forwarding received radio packets while the host catches up, anything else to resume
+pub struct SetRxForwardingCommand {
+    enabled: bool,
+}
+
+impl TryFrom<UartPacketDecoder> for SetRxForwardingCommand {
+    type Error = UartPacketError;
+
+    fn try_from(value: UartPacketDecoder) -> Result<Self, Self::Error> {
+        let (buf, len) = value.get_payload();
+
+        if len < 1 {
+            defmt::error!("SetRxForwardingCommand: require 1 byte while got {} bytes", len);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        Ok(SetRxForwardingCommand { enabled: buf[0] != 0 })
+    }
+}
+
+impl SetRxForwardingCommand {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+}
@@ -0,0 +1,38 @@
+use crate::packet::UartPacketError;
+
+use super::uart_pkt_decoder::UartPacketDecoder;
+
+/// Empty payload saves the config only, otherwise 1 byte of auto-Rx enable and 4 bytes of Rx timeout in ms
+pub struct SettingsSaveCommand {
+    auto_rx_ms: Option<u32>,
+}
+
+impl TryFrom<UartPacketDecoder> for SettingsSaveCommand {
+    type Error = UartPacketError;
+
+    fn try_from(value: UartPacketDecoder) -> Result<Self, Self::Error> {
+        let (buf, len) = value.get_payload();
+
+        if len == 0 {
+            return Ok(SettingsSaveCommand { auto_rx_ms: None });
+        }
+
+        if len < 5 {
+            defmt::error!("SettingsSaveCommand: require 5 bytes while got {} bytes", len);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        let auto_rx_ms = match buf[0] {
+            0 => None,
+            _ => Some(u32::from_le_bytes(buf[1..=4].try_into().unwrap())),
+        };
+
+        Ok(SettingsSaveCommand { auto_rx_ms })
+    }
+}
+
+impl SettingsSaveCommand {
+    pub fn auto_rx_ms(&self) -> Option<u32> {
+        self.auto_rx_ms
+    }
+}
@@ -0,0 +1,35 @@
+use crate::packet::UartPacketError;
+
+use super::uart_pkt_decoder::UartPacketDecoder;
+
+/// Empty payload just asks for a status frame, 4 bytes of interval in ms also start (or with 0, stop) periodic ones
+pub struct GetStatusCommand {
+    interval_ms: Option<u32>,
+}
+
+impl TryFrom<UartPacketDecoder> for GetStatusCommand {
+    type Error = UartPacketError;
+
+    fn try_from(value: UartPacketDecoder) -> Result<Self, Self::Error> {
+        let (buf, len) = value.get_payload();
+
+        if len == 0 {
+            return Ok(GetStatusCommand { interval_ms: None });
+        }
+
+        if len < 4 {
+            defmt::error!("GetStatusCommand: require 4 bytes while got {} bytes", len);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        Ok(GetStatusCommand {
+            interval_ms: Some(u32::from_le_bytes(buf[0..=3].try_into().unwrap())),
+        })
+    }
+}
+
+impl GetStatusCommand {
+    pub fn interval_ms(&self) -> Option<u32> {
+        self.interval_ms
+    }
+}
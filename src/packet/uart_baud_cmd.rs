@@ -0,0 +1,42 @@
+use crate::{packet::UartPacketError, uart_baud::is_supported_baud};
+
+use super::uart_pkt_decoder::UartPacketDecoder;
+
+/// 4 bytes of baud rate, then an optional byte to save it to the settings once it works
+pub struct SetBaudRateCommand {
+    baud: u32,
+    persist: bool,
+}
+
+impl TryFrom<UartPacketDecoder> for SetBaudRateCommand {
+    type Error = UartPacketError;
+
+    fn try_from(value: UartPacketDecoder) -> Result<Self, Self::Error> {
+        let (buf, len) = value.get_payload();
+
+        if len < 4 {
+            defmt::error!("SetBaudRateCommand: require 4 bytes while got {} bytes", len);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        let baud = u32::from_le_bytes(buf[0..=3].try_into().unwrap());
+        if !is_supported_baud(baud) {
+            defmt::error!("SetBaudRateCommand: unsupported baud rate {}", baud);
+            return Err(UartPacketError::CorruptedError);
+        }
+
+        let persist = len >= 5 && buf[4] != 0;
+
+        Ok(SetBaudRateCommand { baud, persist })
+    }
+}
+
+impl SetBaudRateCommand {
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    pub fn persist(&self) -> bool {
+        self.persist
+    }
+}
@@ -15,6 +15,12 @@ impl UartPacketDecoder {
         let mut decoded_len: usize = 0;
         slip_dequeue(queue, &mut buf, &mut decoded_len)?;
 
+        // 1 byte of type, 2 bytes of length and 2 bytes of CRC at least
+        if decoded_len < 5 {
+            defmt::error!("UartPacketDecoder: frame too short: {} bytes", decoded_len);
+            return Err(UartPacketError::CorruptedError);
+        }
+
         let pkt_type = UartPacketType::try_from(buf[0])?;
         let payload_len_bytes: [u8; 2] = [buf[1], buf[2]];
         let curr_payload_len = u16::from_le_bytes(payload_len_bytes);
@@ -50,6 +56,19 @@ impl UartPacketDecoder {
         })
     }
 
+    /// Wrap an already known payload (e.g. settings from flash) so it can go through the usual configurators
+    pub fn from_payload(pkt_type: UartPacketType, payload: &[u8]) -> UartPacketDecoder {
+        let mut payload_buf: [u8; 300] = [0; 300];
+        let len = payload.len().min(payload_buf.len());
+        payload_buf[0..len].copy_from_slice(&payload[0..len]);
+
+        UartPacketDecoder {
+            pkt_type,
+            curr_payload_len: len as u16,
+            payload_buf,
+        }
+    }
+
     pub fn get_type(&self) -> UartPacketType {
         self.pkt_type
     }
@@ -3,11 +3,18 @@ use stm32wlxx_hal::subghz::LoRaPacketStatus;
 
 use crate::constants::{CacheQueue, SLIP_END, SLIP_START};
 
-use super::{slip_enqueue, UartPacketType, CRC};
+use crate::stats::{self, Stat};
 
+use super::{enqueue_ditch_oldest, free_space, max_encoded_len, slip_enqueue, UartPacketType, CRC};
+
+/// Nothing goes into the queue until the payload length is known. If the worst case of the whole frame
+/// doesn't fit then, the frame is dropped as a whole instead of pushing out the start of an older one.
 pub struct UartPacketEncoder<'a> {
     queue: &'a mut CacheQueue,
     digest: Digest<'a, u16>,
+    pkt_type: UartPacketType,
+    started: bool,
+    dropped: bool,
 }
 
 impl<'a> UartPacketEncoder<'a> {
@@ -17,15 +24,17 @@ impl<'a> UartPacketEncoder<'a> {
         pkt.finalize()
     }
 
-    pub fn make_pong(queue: &'a mut CacheQueue) {
+    /// Carries 2 bytes of Rx credits, see `UartDma::rx_credits`
+    pub fn make_pong(queue: &'a mut CacheQueue, credits: u16) {
         let mut pkt = UartPacketEncoder::new(UartPacketType::Pong, queue);
-        pkt.add_packet_len(0);
+        pkt.add_payload(&credits.to_le_bytes());
         pkt.finalize()
     }
 
-    pub fn make_ack(queue: &'a mut CacheQueue) {
+    /// Carries 2 bytes of Rx credits, see `UartDma::rx_credits`
+    pub fn make_ack(queue: &'a mut CacheQueue, credits: u16) {
         let mut pkt = UartPacketEncoder::new(UartPacketType::Ack, queue);
-        pkt.add_packet_len(0);
+        pkt.add_payload(&credits.to_le_bytes());
         pkt.finalize()
     }
 
@@ -39,28 +48,67 @@ impl<'a> UartPacketEncoder<'a> {
         let mut digest = CRC.digest();
         digest.update(&[pkt_type as u8]);
 
-        match queue.enqueue(SLIP_START) {
-            Ok(_) => {}
-            Err(b) => {
-                defmt::warn!("UartPacket: Rx buffer full! Ditching oldest");
-                queue.dequeue(); // Drop the oldest
-                queue.enqueue(b).unwrap();
-            }
+        UartPacketEncoder {
+            queue,
+            digest,
+            pkt_type,
+            started: false,
+            dropped: false,
+        }
+    }
+
+    // Check for room once, before the first byte of the frame
+    fn begin(&mut self, payload_len: usize) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+
+        if free_space(self.queue) < max_encoded_len(payload_len) {
+            defmt::warn!(
+                "UartPacketEncoder: no room for {:?}, len={}",
+                self.pkt_type,
+                payload_len
+            );
+            stats::count(Stat::UartTxFramesDropped);
+            self.dropped = true;
+            return;
         }
 
-        slip_enqueue(queue, pkt_type as u8);
-        UartPacketEncoder { queue, digest }
+        enqueue_ditch_oldest(self.queue, SLIP_START);
+        slip_enqueue(self.queue, self.pkt_type as u8);
     }
 
     pub fn add_packet_len(&mut self, pkt_len: usize) {
+        self.begin(pkt_len);
+        if self.dropped {
+            return;
+        }
+
         let pkt_len_bytes: [u8; 2] = (pkt_len as u16).to_le_bytes();
         self.digest.update(&pkt_len_bytes);
         slip_enqueue(self.queue, pkt_len_bytes[0]);
         slip_enqueue(self.queue, pkt_len_bytes[1]);
     }
 
+    pub fn add_payload(&mut self, payload: &[u8]) {
+        self.add_packet_len(payload.len());
+        if self.dropped {
+            return;
+        }
+
+        self.digest.update(payload);
+        for b in payload {
+            slip_enqueue(self.queue, *b);
+        }
+    }
+
     pub fn add_payload_with_lora_status(&mut self, payload: &[u8], data_len: u8, pkt_status: LoRaPacketStatus) {
+        self.begin(4 + payload.len());
         self.add_packet_len((2 + data_len as usize) as usize); // 2 bytes of RSSI and SNR, plus data length
+        if self.dropped {
+            return;
+        }
 
         let pkt_rssi = pkt_status.signal_rssi_pkt().to_integer();
         let pkt_rssi_bytes: [u8; 2] = pkt_rssi.to_le_bytes();
@@ -84,18 +132,17 @@ impl<'a> UartPacketEncoder<'a> {
         }
     }
 
-    pub fn finalize(self) {
+    pub fn finalize(mut self) {
+        self.begin(0);
+        if self.dropped {
+            return;
+        }
+
         let checksum: [u8; 2] = self.digest.finalize().to_le_bytes();
         slip_enqueue(self.queue, checksum[0]);
         slip_enqueue(self.queue, checksum[1]);
 
-        match self.queue.enqueue(SLIP_END) {
-            Ok(_) => {}
-            Err(b) => {
-                defmt::warn!("radio: Rx buffer full! Ditching oldest");
-                self.queue.dequeue(); // Drop the oldest
-                self.queue.enqueue(b).unwrap();
-            }
-        }
+        enqueue_ditch_oldest(self.queue, SLIP_END);
+        stats::count(Stat::UartFramesTx);
     }
 }
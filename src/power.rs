@@ -4,6 +4,8 @@ use stm32wlxx_hal::{
     rcc,
 };
 
+use crate::watchdog::feed_watchdog_now;
+
 const SCB_SCR_SLEEPDEEP: u32 = 0x1 << 2;
 
 pub fn enter_stop2_mode() {
@@ -31,6 +33,9 @@ pub fn enter_stop2_mode() {
 
         (*pac::SCB::PTR).scr.modify(|scr| scr | SCB_SCR_SLEEPDEEP);
 
+        // Nothing feeds the IWDG in STOP2, this gives the host the full timeout to wake us up again
+        feed_watchdog_now();
+
         cortex_m::asm::wfi();
 
         let cs = &CriticalSection::new();
@@ -1,35 +1,247 @@
+use core::ptr;
+
 use stm32wlxx_hal::{
+    gpio::{
+        pins::{B8, C13},
+        Output,
+    },
+    pac,
     spi::{Error, SgMiso, SgMosi},
-    subghz::{CfgIrq, FallbackMode, Irq, Ocp, RegMode, StandbyClk, SubGhz, Timeout},
+    subghz::{
+        BpskPacketParams, CfgIrq, FallbackMode, GenericPacketParams, Irq, LoRaPacketParams, LoRaPacketStatus, Ocp,
+        RegMode, SleepCfg, StandbyClk, SubGhz, Timeout,
+    },
 };
 
 use crate::{
     constants::CacheQueue,
     packet::{uart_pkt_encoder::UartPacketEncoder, UartPacketType},
+    radio_state::{IllegalTransition, RadioMode, RadioState},
 };
 
 const IRQ_CFG: CfgIrq = CfgIrq::new()
     .irq_enable_all(Irq::TxDone)
     .irq_enable_all(Irq::RxDone)
     .irq_enable_all(Irq::Timeout)
+    .irq_enable_all(Irq::PreambleDetected)
+    .irq_enable_all(Irq::SyncDetected)
     .irq_enable_all(Irq::HeaderErr)
     .irq_enable_all(Irq::Err);
 const TX_BUF_OFFSET: u8 = 0;
 const RX_BUF_OFFSET: u8 = 0;
 
-fn radio_encode_packet(radio: &mut SubGhz<SgMiso, SgMosi>, rx_queue: &mut CacheQueue) -> Result<(), Error> {
+const OP_WRITE_REGISTER: u8 = 0x0d;
+const OP_READ_REGISTER: u8 = 0x1d;
+const REG_GFSK_NODE_ADDR: u16 = 0x06cd;
+const REG_GFSK_BROADCAST_ADDR: u16 = 0x06ce;
+const REG_RTC_PERIOD: u16 = 0x0906; // 24-bit, big endian, in 15.625us steps
+const RTC_PERIOD_MAX: u32 = 0x00ff_ffff;
+const RTC_TICKS_PER_MS: u32 = 64;
+const REG_IQ_POLARITY: u16 = 0x0736;
+const BUSY_SPIN_LIMIT: u32 = 2_000; // Roughly 10ms at 1MHz LPRun, well past the slowest command (calibration)
+
+fn subghz_spi_xfer(b: u8) -> u8 {
+    unsafe {
+        let spi = &*pac::SPI3::PTR;
+        while spi.sr.read().txe().bit_is_clear() {}
+        ptr::write_volatile(spi.dr.as_ptr() as *mut u8, b); // Must be a byte access, or the FIFO takes two bytes
+        while spi.sr.read().rxne().bit_is_clear() {}
+        ptr::read_volatile(spi.dr.as_ptr() as *const u8)
+    }
+}
+
+/// `spi::Error` has no timeout, so a BUSY that never goes low comes back as a mode fault.
+/// Either way it's an SPI error, and the caller resets the radio over it.
+fn subghz_spi_begin() -> Result<(), Error> {
+    let pwr = unsafe { &*pac::PWR::PTR };
+    for _ in 0..BUSY_SPIN_LIMIT {
+        if pwr.sr2.read().rfbusys().bit_is_clear() {
+            pwr.subghzspicr.write(|w| w.nss().clear_bit());
+            return Ok(());
+        }
+    }
+
+    defmt::error!("radio: BUSY stuck, register access given up");
+    Err(Error::ModeFault)
+}
+
+fn subghz_spi_end() {
+    unsafe {
+        (*pac::PWR::PTR).subghzspicr.write(|w| w.nss().set_bit());
+    }
+}
+
+// The HAL doesn't expose every SubGHz register, so we talk to those directly.
+// Borrowing the `SubGhz` makes sure nobody else is using the SPI at the same time.
+pub fn write_radio_register(_radio: &mut SubGhz<SgMiso, SgMosi>, addr: u16, data: &[u8]) -> Result<(), Error> {
+    let addr_bytes: [u8; 2] = addr.to_be_bytes();
+
+    subghz_spi_begin()?;
+    subghz_spi_xfer(OP_WRITE_REGISTER);
+    subghz_spi_xfer(addr_bytes[0]);
+    subghz_spi_xfer(addr_bytes[1]);
+    for b in data {
+        subghz_spi_xfer(*b);
+    }
+    subghz_spi_end();
+
+    Ok(())
+}
+
+pub fn read_radio_register(_radio: &mut SubGhz<SgMiso, SgMosi>, addr: u16, data: &mut [u8]) -> Result<(), Error> {
+    let addr_bytes: [u8; 2] = addr.to_be_bytes();
+
+    subghz_spi_begin()?;
+    subghz_spi_xfer(OP_READ_REGISTER);
+    subghz_spi_xfer(addr_bytes[0]);
+    subghz_spi_xfer(addr_bytes[1]);
+    subghz_spi_xfer(0); // Status byte
+    for b in data.iter_mut() {
+        *b = subghz_spi_xfer(0);
+    }
+    subghz_spi_end();
+
+    Ok(())
+}
+
+pub fn set_radio_gfsk_addr(radio: &mut SubGhz<SgMiso, SgMosi>, node_addr: u8, broadcast_addr: u8) -> Result<(), Error> {
+    write_radio_register(radio, REG_GFSK_NODE_ADDR, &[node_addr])?;
+    write_radio_register(radio, REG_GFSK_BROADCAST_ADDR, &[broadcast_addr])?;
+
+    Ok(())
+}
+
+/// Program the sub-GHz RTC for waking up from sleep, in ms (saturates at ~262s)
+pub fn set_radio_rtc_wakeup(radio: &mut SubGhz<SgMiso, SgMosi>, wakeup_ms: u32) -> Result<(), Error> {
+    let period = wakeup_ms.saturating_mul(RTC_TICKS_PER_MS).min(RTC_PERIOD_MAX);
+    write_radio_register(radio, REG_RTC_PERIOD, &period.to_be_bytes()[1..])?;
+
+    Ok(())
+}
+
+/// LoRa packet parameters with separate Tx and Rx IQ polarity, see `PacketParams`
+#[derive(Clone, Copy)]
+pub struct LoRaIqConfig {
+    pkt_params: LoRaPacketParams,
+    tx_invert_iq: bool,
+    rx_invert_iq: bool,
+}
+
+impl LoRaIqConfig {
+    pub const fn new(pkt_params: LoRaPacketParams, tx_invert_iq: bool, rx_invert_iq: bool) -> LoRaIqConfig {
+        LoRaIqConfig {
+            pkt_params,
+            tx_invert_iq,
+            rx_invert_iq,
+        }
+    }
+
+    /// Payload length is what's about to be sent, not the configured one
+    pub fn apply_tx(&self, radio: &mut SubGhz<SgMiso, SgMosi>, len: u8) -> Result<(), Error> {
+        self.apply(radio, self.pkt_params.set_payload_len(len), self.tx_invert_iq)
+    }
+
+    /// Configured payload length goes back, it's the max (or fixed) length to receive
+    pub fn apply_rx(&self, radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), Error> {
+        self.apply(radio, self.pkt_params, self.rx_invert_iq)
+    }
+
+    fn apply(
+        &self,
+        radio: &mut SubGhz<SgMiso, SgMosi>,
+        pkt_params: LoRaPacketParams,
+        invert_iq: bool,
+    ) -> Result<(), Error> {
+        radio.set_lora_packet_params(&pkt_params.set_invert_iq(invert_iq))?;
+
+        // SX126x errata: bit 2 of the IQ polarity register must be cleared for inverted IQ
+        // and set for standard IQ, otherwise the packets get lost after a few in a row
+        let mut iq_polarity: [u8; 1] = [0];
+        read_radio_register(radio, REG_IQ_POLARITY, &mut iq_polarity)?;
+        if invert_iq {
+            iq_polarity[0] &= !(1 << 2);
+        } else {
+            iq_polarity[0] |= 1 << 2;
+        }
+        write_radio_register(radio, REG_IQ_POLARITY, &iq_polarity)?;
+
+        Ok(())
+    }
+}
+
+pub fn read_radio_packet(
+    radio: &mut SubGhz<SgMiso, SgMosi>,
+    output_buf: &mut [u8; 256],
+) -> Result<(usize, LoRaPacketStatus), Error> {
     let pkt_status = radio.lora_packet_status()?;
 
-    let mut output_buf: [u8; 256] = [0; 256];
     let (_, data_len, ptr) = radio.rx_buffer_status()?;
     radio.read_buffer(ptr, &mut output_buf[0..(data_len as usize)])?;
 
     defmt::info!("radio: RxDone, got {:?}; len={}", pkt_status, data_len);
+    Ok((data_len as usize, pkt_status))
+}
+
+pub fn encode_radio_packet(payload: &[u8], pkt_status: LoRaPacketStatus, rx_queue: &mut CacheQueue) {
     let mut encoder = UartPacketEncoder::new(UartPacketType::RadioReceivedPacket, rx_queue);
-    encoder.add_payload_with_lora_status(&output_buf[0..(data_len as usize)], data_len, pkt_status);
+    encoder.add_payload_with_lora_status(payload, payload.len() as u8, pkt_status);
     encoder.finalize();
+}
 
-    Ok(())
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum RadioFaultKind {
+    HeaderError = 0,
+    CrcError = 1,
+    TxTimeout = 2,
+    DeviceError = 3,
+}
+
+/// Tell the host something went wrong on the radio side, along with the raw IRQ and device error bits
+pub fn encode_radio_fault(kind: RadioFaultKind, irq: u16, op_error: u16, queue: &mut CacheQueue) {
+    let irq_bytes: [u8; 2] = irq.to_le_bytes();
+    let op_error_bytes: [u8; 2] = op_error.to_le_bytes();
+    let mut encoder = UartPacketEncoder::new(UartPacketType::RadioFault, queue);
+    encoder.add_payload(&[
+        kind as u8,
+        irq_bytes[0],
+        irq_bytes[1],
+        op_error_bytes[0],
+        op_error_bytes[1],
+    ]);
+    encoder.finalize();
+}
+
+/// Read the device errors (PLL lock, PA ramp, calibration etc.) and clear them if there's any
+pub fn read_radio_op_error(radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<u16, Error> {
+    let (_, op_error) = radio.op_error()?;
+    if op_error != 0 {
+        defmt::warn!("radio: device error 0x{:x}", op_error);
+        radio.clear_error()?;
+    }
+
+    Ok(op_error)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum RadioError {
+    Spi(Error),
+    IllegalTransition { from: RadioMode, to: RadioMode },
+}
+
+impl From<Error> for RadioError {
+    fn from(value: Error) -> Self {
+        RadioError::Spi(value)
+    }
+}
+
+impl From<IllegalTransition> for RadioError {
+    fn from(value: IllegalTransition) -> Self {
+        RadioError::IllegalTransition {
+            from: value.from,
+            to: value.to,
+        }
+    }
 }
 
 pub fn setup_radio(radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), Error> {
@@ -44,31 +256,96 @@ pub fn setup_radio(radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn start_radio_rx(radio: &mut SubGhz<SgMiso, SgMosi>, timeout_ms: u32) -> Result<(), Error> {
+/// Put the radio back to standby with the base setup after an SPI failure.
+/// Modulation and frequency settings are left as they are, whatever was on air is gone.
+pub fn reset_radio(radio: &mut SubGhz<SgMiso, SgMosi>, state: &mut RadioState) -> Result<(), Error> {
+    setup_radio(radio)?;
+    let (_, irq) = radio.irq_status()?;
+    radio.clear_irq_status(irq)?;
+    state.set(RadioMode::Standby);
+
+    Ok(())
+}
+
+/// Packet parameters of the active modulation. Tx sets the payload length to what's sent,
+/// Rx puts the configured one back, so both `start_radio_tx` and `start_radio_rx` re-apply them.
+#[derive(Clone, Copy)]
+pub enum PacketParams {
+    LoRa(LoRaIqConfig),
+    Gfsk(GenericPacketParams),
+    Bpsk(BpskPacketParams),
+}
+
+impl PacketParams {
+    pub fn apply_tx(&self, radio: &mut SubGhz<SgMiso, SgMosi>, len: u8) -> Result<(), Error> {
+        match self {
+            PacketParams::LoRa(iq) => iq.apply_tx(radio, len),
+            PacketParams::Gfsk(params) => radio.set_packet_params(&params.set_payload_len(len)),
+            PacketParams::Bpsk(params) => radio.set_bpsk_packet_params(&params.set_payload_len(len)),
+        }
+    }
+
+    pub fn apply_rx(&self, radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), Error> {
+        match self {
+            PacketParams::LoRa(iq) => iq.apply_rx(radio),
+            PacketParams::Gfsk(params) => radio.set_packet_params(params),
+            PacketParams::Bpsk(_) => Ok(()), // Tx only
+        }
+    }
+}
+
+/// Point the RF switch at the Rx path, do this before `start_radio_rx`
+pub fn rf_switch_rx(rf_sw_1: &mut Output<B8>, rf_sw_2: &mut Output<C13>) {
+    rf_sw_1.set_level_high();
+    rf_sw_2.set_level_low();
+}
+
+/// Point the RF switch at the Tx path, do this before `start_radio_tx`
+pub fn rf_switch_tx(rf_sw_1: &mut Output<B8>, rf_sw_2: &mut Output<C13>) {
+    rf_sw_1.set_level_low();
+    rf_sw_2.set_level_high();
+}
+
+pub fn start_radio_rx(
+    radio: &mut SubGhz<SgMiso, SgMosi>,
+    state: &mut RadioState,
+    pkt_params: Option<&PacketParams>,
+    timeout_ms: u32,
+) -> Result<(), RadioError> {
     defmt::info!("radio: start Rx, timeout={}", timeout_ms);
+    let next = RadioMode::Rx { timeout_ms };
+    state.check(next)?;
+    if let Some(params) = pkt_params {
+        params.apply_rx(radio)?;
+    }
+
     if timeout_ms == 0 || timeout_ms == u32::MAX {
         radio.set_rx(Timeout::DISABLED)?;
     } else {
         radio.set_rx(Timeout::from_millis_sat(timeout_ms))?;
     }
 
+    state.set(next);
     Ok(())
 }
 
-pub fn handle_radio_rx_done(
+pub fn start_radio_tx(
     radio: &mut SubGhz<SgMiso, SgMosi>,
-    irq: u16,
-    rx_queue: &mut CacheQueue,
-) -> Result<(), Error> {
-    if irq & Irq::RxDone.mask() != 0 {
-        radio_encode_packet(radio, rx_queue)?;
-        return Ok(());
-    }
+    state: &mut RadioState,
+    pkt_params: Option<&PacketParams>,
+    tx_buf: &[u8],
+    timeout_ms: u32,
+) -> Result<(), RadioError> {
+    let next = RadioMode::Tx {
+        len: tx_buf.len() as u8,
+        timeout_ms,
+    };
+    state.check(next)?;
 
-    Ok(())
-}
+    if let Some(params) = pkt_params {
+        params.apply_tx(radio, tx_buf.len() as u8)?;
+    }
 
-pub fn start_radio_tx(radio: &mut SubGhz<SgMiso, SgMosi>, tx_buf: &[u8], timeout_ms: u32) -> Result<(), Error> {
     radio.write_buffer(TX_BUF_OFFSET, tx_buf)?;
     let (_, irq) = radio.irq_status()?;
     radio.clear_irq_status(irq)?;
@@ -79,11 +356,25 @@ pub fn start_radio_tx(radio: &mut SubGhz<SgMiso, SgMosi>, tx_buf: &[u8], timeout
         radio.set_tx(Timeout::from_millis_sat(timeout_ms))?;
     }
 
+    state.set(next);
     Ok(())
 }
 
-pub fn set_radio_to_standby(radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), Error> {
+pub fn set_radio_to_standby(radio: &mut SubGhz<SgMiso, SgMosi>, state: &mut RadioState) -> Result<(), Error> {
     radio.set_standby(StandbyClk::Rc)?;
+    state.set(RadioMode::Standby);
+
+    Ok(())
+}
+
+pub fn set_radio_to_sleep(
+    radio: &mut SubGhz<SgMiso, SgMosi>,
+    state: &mut RadioState,
+    cfg: SleepCfg,
+    warm: bool,
+) -> Result<(), Error> {
+    unsafe { radio.set_sleep(cfg)? };
+    state.set(RadioMode::Sleep { warm });
 
     Ok(())
 }
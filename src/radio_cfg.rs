@@ -0,0 +1,196 @@
+use stm32wlxx_hal::{
+    spi::{SgMiso, SgMosi},
+    subghz::{self, SubGhz},
+};
+
+use crate::{
+    constants::{CacheQueue, DEFAULT_FREQ_CFG, DEFAULT_LORA_CFG, DEFAULT_PHY_CFG},
+    error::Error,
+    packet::{
+        radio_bpsk_cfg::RadioBpskConfigurator, radio_freq_cfg::RadioFreqConfigurator,
+        radio_gfsk_cfg::RadioGfskConfigurator, radio_lora_cfg::RadioLoraConfigurator,
+        radio_phy_cfg::RadioPhyConfigurator, uart_pkt_decoder::UartPacketDecoder, uart_pkt_encoder::UartPacketEncoder,
+        UartPacketType,
+    },
+    radio::{reset_radio, PacketParams},
+    radio_state::RadioState,
+};
+
+pub enum ActiveModulation {
+    LoRa(RadioLoraConfigurator),
+    Gfsk(RadioGfskConfigurator),
+    Bpsk(RadioBpskConfigurator),
+}
+
+/// The configurators last applied successfully to the radio, kept around instead of dropped
+/// so that temporary changes (e.g. per-packet overrides) can be put back afterwards.
+pub struct ActiveRadioConfig {
+    phy: Option<RadioPhyConfigurator>,
+    freq: Option<RadioFreqConfigurator>,
+    modulation: Option<ActiveModulation>,
+}
+
+impl Default for ActiveRadioConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ActiveRadioConfig {
+    pub const fn new() -> ActiveRadioConfig {
+        ActiveRadioConfig {
+            phy: None,
+            freq: None,
+            modulation: None,
+        }
+    }
+
+    pub fn set_phy(&mut self, cfg: RadioPhyConfigurator) {
+        self.phy = Some(cfg);
+    }
+
+    pub fn set_freq(&mut self, cfg: RadioFreqConfigurator) {
+        self.freq = Some(cfg);
+    }
+
+    pub fn set_modulation(&mut self, modulation: ActiveModulation) {
+        self.modulation = Some(modulation);
+    }
+
+    pub fn phy(&self) -> Option<&RadioPhyConfigurator> {
+        self.phy.as_ref()
+    }
+
+    pub fn freq(&self) -> Option<&RadioFreqConfigurator> {
+        self.freq.as_ref()
+    }
+
+    pub fn lora(&self) -> Option<&RadioLoraConfigurator> {
+        match &self.modulation {
+            Some(ActiveModulation::LoRa(cfg)) => Some(cfg),
+            _ => None,
+        }
+    }
+
+    pub fn gfsk(&self) -> Option<&RadioGfskConfigurator> {
+        match &self.modulation {
+            Some(ActiveModulation::Gfsk(cfg)) => Some(cfg),
+            _ => None,
+        }
+    }
+
+    pub fn bpsk(&self) -> Option<&RadioBpskConfigurator> {
+        match &self.modulation {
+            Some(ActiveModulation::Bpsk(cfg)) => Some(cfg),
+            _ => None,
+        }
+    }
+
+    /// BPSK is Tx only on the SubGHz radio, so there's no Rx to go back to with it
+    pub fn can_receive(&self) -> bool {
+        self.bpsk().is_none()
+    }
+
+    /// The modulation config as the set command it came from, if there's one
+    pub fn modulation_bytes(&self) -> Option<(UartPacketType, &[u8])> {
+        match &self.modulation {
+            Some(ActiveModulation::LoRa(cfg)) => Some((UartPacketType::RadioLoraConfig, cfg.as_bytes())),
+            Some(ActiveModulation::Gfsk(cfg)) => Some((UartPacketType::RadioGfskConfig, cfg.as_bytes())),
+            Some(ActiveModulation::Bpsk(cfg)) => Some((UartPacketType::RadioBpskConfig, cfg.as_bytes())),
+            None => None,
+        }
+    }
+
+    /// Answer a `Get*Config` request with the matching set command, so the host can send it back as-is.
+    /// Returns false if that part hasn't been configured (or a different modulation is active).
+    pub fn encode_readback(&self, req: UartPacketType, queue: &mut CacheQueue) -> bool {
+        let (pkt_type, payload) = match req {
+            UartPacketType::GetRadioPhyConfig => (UartPacketType::RadioPhyConfig, self.phy().map(|c| c.as_bytes())),
+            UartPacketType::GetRadioFreqConfig => (UartPacketType::RadioFreqConfig, self.freq().map(|c| c.as_bytes())),
+            UartPacketType::GetRadioLoraConfig => (UartPacketType::RadioLoraConfig, self.lora().map(|c| c.as_bytes())),
+            UartPacketType::GetRadioGfskConfig => (UartPacketType::RadioGfskConfig, self.gfsk().map(|c| c.as_bytes())),
+            UartPacketType::GetRadioBpskConfig => (UartPacketType::RadioBpskConfig, self.bpsk().map(|c| c.as_bytes())),
+            _ => return false,
+        };
+
+        let payload = match payload {
+            Some(payload) => payload,
+            None => return false,
+        };
+
+        let mut encoder = UartPacketEncoder::new(pkt_type, queue);
+        encoder.add_payload(payload);
+        encoder.finalize();
+        true
+    }
+
+    /// Apply everything again in the order the host normally sends it, e.g. after the radio got reset
+    pub fn replay(&self, radio: &mut SubGhz<SgMiso, SgMosi>) -> Result<(), subghz::Error> {
+        if let Some(phy) = &self.phy {
+            phy.configure_radio(radio)?;
+        }
+
+        if let Some(freq) = &self.freq {
+            freq.configure_radio(radio)?;
+        }
+
+        match &self.modulation {
+            Some(ActiveModulation::LoRa(cfg)) => cfg.configure_radio(radio)?,
+            Some(ActiveModulation::Gfsk(cfg)) => cfg.configure_radio(radio)?,
+            Some(ActiveModulation::Bpsk(cfg)) => cfg.configure_radio(radio)?,
+            None => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Bring the radio back from a cold start sleep, putting the host's settings back before any Tx/Rx.
+/// Does nothing after a warm start sleep, the radio still has everything then.
+pub fn wake_radio(
+    radio: &mut SubGhz<SgMiso, SgMosi>,
+    state: &mut RadioState,
+    active: &ActiveRadioConfig,
+) -> Result<(), subghz::Error> {
+    if !state.config_lost() {
+        return Ok(());
+    }
+
+    reset_radio(radio, state)?;
+    active.replay(radio)?;
+
+    defmt::info!("wake_radio: configuration replayed after cold sleep");
+    Ok(())
+}
+
+/// Configure the compiled-in default profile (see `constants`), replacing whatever the host set before
+pub fn apply_factory_defaults(
+    radio: &mut SubGhz<SgMiso, SgMosi>,
+    active: &mut ActiveRadioConfig,
+    pkt_params: &mut Option<PacketParams>,
+) -> Result<(), Error> {
+    let phy = RadioPhyConfigurator::try_from(UartPacketDecoder::from_payload(
+        UartPacketType::RadioPhyConfig,
+        &DEFAULT_PHY_CFG,
+    ))?;
+    let freq = RadioFreqConfigurator::try_from(UartPacketDecoder::from_payload(
+        UartPacketType::RadioFreqConfig,
+        &DEFAULT_FREQ_CFG,
+    ))?;
+    let lora = RadioLoraConfigurator::try_from(UartPacketDecoder::from_payload(
+        UartPacketType::RadioLoraConfig,
+        &DEFAULT_LORA_CFG,
+    ))?;
+
+    phy.configure_radio(radio)?;
+    freq.configure_radio(radio)?;
+    lora.configure_radio(radio)?;
+
+    *pkt_params = Some(lora.packet_params());
+    active.set_phy(phy);
+    active.set_freq(freq);
+    active.set_modulation(ActiveModulation::LoRa(lora));
+
+    defmt::info!("apply_factory_defaults: default profile applied");
+    Ok(())
+}
@@ -0,0 +1,241 @@
+use stm32wlxx_hal::{
+    pac,
+    spi::{SgMiso, SgMosi},
+    subghz::{OpError, SubGhz},
+};
+
+use crate::{
+    constants::CacheQueue,
+    error::Error,
+    packet::{uart_pkt_encoder::UartPacketEncoder, UartPacketType},
+    radio::setup_radio,
+    radio_cfg::ActiveRadioConfig,
+    radio_state::{RadioMode, RadioState},
+};
+
+const BUSY_STUCK_MS: u32 = 1500; // Sampled once a second, so three looks in a row at BUSY high
+const RESET_SPIN_LIMIT: u32 = 10_000; // Roughly 50ms at 1MHz LPRun, the radio is out of reset in a few ms
+const SPI_ERROR_LIMIT: u8 = 3;
+const IRQ_GRACE_MS: u32 = 1000;
+const MAX_CONSECUTIVE_RECOVERIES: u8 = 3;
+
+// PA ramp and image calibration errors are left out: a reset won't fix a bad PA or frequency setting
+const FATAL_OP_ERRORS: u16 = OpError::PllLockError.mask()
+    | OpError::XoscStartError.mask()
+    | OpError::AdcCalibrationError.mask()
+    | OpError::PllCalibrationError.mask()
+    | OpError::RcCalibrationError.mask();
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum RecoveryCause {
+    BusyStuck = 0,
+    SpiErrors = 1,
+    MissingIrq = 2,
+    DeviceError = 3,
+}
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum RecoveryResult {
+    Recovered = 0,
+    Failed = 1,
+    GaveUp = 2,
+}
+
+/// Keeps an eye on the radio and decides when it's time to pull it through reset
+pub struct RadioHealth {
+    spi_errors: u8,
+    pending: Option<RecoveryCause>,
+    watched_op: u32,
+    watched_since_ms: u32,
+    busy_since_ms: Option<u32>,
+    consecutive: u8,
+    recoveries: u32,
+    gave_up: bool,
+}
+
+impl Default for RadioHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RadioHealth {
+    pub const fn new() -> RadioHealth {
+        RadioHealth {
+            spi_errors: 0,
+            pending: None,
+            watched_op: 0,
+            watched_since_ms: 0,
+            busy_since_ms: None,
+            consecutive: 0,
+            recoveries: 0,
+            gave_up: false,
+        }
+    }
+
+    /// The radio did what it was told, so whatever went wrong before is over
+    pub fn record_ok(&mut self) {
+        self.spi_errors = 0;
+        self.consecutive = 0;
+        self.gave_up = false;
+    }
+
+    /// Returns true once SPI failed too many times in a row and a recovery got requested
+    pub fn record_spi_error(&mut self) -> bool {
+        self.spi_errors = self.spi_errors.saturating_add(1);
+        if self.spi_errors >= SPI_ERROR_LIMIT {
+            self.request(RecoveryCause::SpiErrors);
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns true if the device errors are bad enough to request a recovery
+    pub fn record_op_error(&mut self, op_error: u16) -> bool {
+        if op_error & FATAL_OP_ERRORS != 0 {
+            self.request(RecoveryCause::DeviceError);
+            return true;
+        }
+
+        false
+    }
+
+    pub fn request(&mut self, cause: RecoveryCause) {
+        defmt::warn!("RadioHealth: recovery requested, {:?}", cause);
+        self.pending = Some(cause);
+    }
+
+    pub fn take_request(&mut self) -> Option<RecoveryCause> {
+        self.pending.take()
+    }
+
+    /// Called periodically, works out whether the IRQ for the ongoing Tx/Rx is overdue
+    pub fn check_irq_overdue(&mut self, state: &RadioState, now_ms: u32) -> Option<RecoveryCause> {
+        if state.op_count() != self.watched_op {
+            self.watched_op = state.op_count();
+            self.watched_since_ms = now_ms;
+            return None;
+        }
+
+        let timeout_ms = match state.mode() {
+            RadioMode::Tx { timeout_ms, .. } | RadioMode::Rx { timeout_ms } => timeout_ms,
+            _ => return None,
+        };
+
+        // No timeout means no IRQ to wait for
+        if timeout_ms == 0 || timeout_ms == u32::MAX {
+            return None;
+        }
+
+        if now_ms.wrapping_sub(self.watched_since_ms) > timeout_ms.saturating_add(IRQ_GRACE_MS) {
+            return Some(RecoveryCause::MissingIrq);
+        }
+
+        None
+    }
+
+    /// Called periodically with a single look at BUSY, it only counts as stuck once it stayed high across a few of them.
+    /// BUSY is held high all the time in sleep, nothing to tell from it then.
+    pub fn check_busy_stuck(&mut self, state: &RadioState, now_ms: u32) -> Option<RecoveryCause> {
+        if matches!(state.mode(), RadioMode::Sleep { .. }) || !is_radio_busy() {
+            self.busy_since_ms = None;
+            return None;
+        }
+
+        let since_ms = *self.busy_since_ms.get_or_insert(now_ms);
+        if now_ms.wrapping_sub(since_ms) >= BUSY_STUCK_MS {
+            return Some(RecoveryCause::BusyStuck);
+        }
+
+        None
+    }
+
+    /// Returns false once recoveries keep failing, so we stop hammering a dead radio
+    pub fn begin_recovery(&mut self) -> bool {
+        if self.consecutive >= MAX_CONSECUTIVE_RECOVERIES {
+            return false;
+        }
+
+        self.consecutive += 1;
+        self.recoveries = self.recoveries.saturating_add(1);
+        self.spi_errors = 0;
+        self.busy_since_ms = None;
+        true
+    }
+
+    /// Returns true the first time only, so the host hears about giving up once
+    pub fn mark_gave_up(&mut self) -> bool {
+        let first = !self.gave_up;
+        self.gave_up = true;
+        first
+    }
+
+    pub fn recoveries(&self) -> u32 {
+        self.recoveries
+    }
+}
+
+/// BUSY stays high while the radio works on a command (or sleeps)
+pub fn is_radio_busy() -> bool {
+    unsafe { (*pac::PWR::PTR).sr2.read().rfbusys().bit_is_set() }
+}
+
+/// Spin until `done` holds, false if it never did
+fn spin_until(done: impl Fn() -> bool) -> bool {
+    (0..RESET_SPIN_LIMIT).any(|_| done())
+}
+
+/// Pull the SubGHz radio through RCC reset, then bring back the base setup and the last configuration.
+/// The radio ends up in standby, resuming Rx/Tx is up to the caller.
+pub fn recover_radio(
+    radio: &mut SubGhz<SgMiso, SgMosi>,
+    state: &mut RadioState,
+    active: &ActiveRadioConfig,
+) -> Result<(), Error> {
+    let rcc = unsafe { &*pac::RCC::PTR };
+
+    // RFRSTF follows RFRST only once the radio actually went into (and came out of) reset
+    rcc.csr.modify(|_, w| w.rfrst().set_bit());
+    let entered = spin_until(|| rcc.csr.read().rfrstf().bit_is_set());
+    rcc.csr.modify(|_, w| w.rfrst().clear_bit());
+    let released = spin_until(|| rcc.csr.read().rfrstf().bit_is_clear());
+    state.set(RadioMode::Standby);
+
+    if !entered || !released {
+        defmt::error!(
+            "recover_radio: reset flag stuck, entered={}, released={}",
+            entered,
+            released
+        );
+        return Err(Error::RadioBusy);
+    }
+
+    if !spin_until(|| !is_radio_busy()) {
+        defmt::error!("recover_radio: still busy after reset");
+        return Err(Error::RadioBusy);
+    }
+
+    setup_radio(radio)?;
+    active.replay(radio)?;
+
+    defmt::info!("recover_radio: radio reset and configuration replayed");
+    Ok(())
+}
+
+/// 1 byte of cause, 1 byte of result, then 4 bytes of recoveries so far
+pub fn encode_recovery_event(cause: RecoveryCause, result: RecoveryResult, recoveries: u32, queue: &mut CacheQueue) {
+    let count_bytes: [u8; 4] = recoveries.to_le_bytes();
+    let mut encoder = UartPacketEncoder::new(UartPacketType::RadioRecovery, queue);
+    encoder.add_payload(&[
+        cause as u8,
+        result as u8,
+        count_bytes[0],
+        count_bytes[1],
+        count_bytes[2],
+        count_bytes[3],
+    ]);
+    encoder.finalize();
+}
@@ -0,0 +1,379 @@
+// Radio mode bookkeeping, kept away from the HAL so that it can be unit tested on the host:
+// cargo test --lib --target x86_64-unknown-linux-gnu
+
+// IRQ status bits, same as `subghz::Irq`
+const IRQ_TX_DONE: u16 = 1 << 0;
+const IRQ_RX_DONE: u16 = 1 << 1;
+const IRQ_PREAMBLE_DETECTED: u16 = 1 << 2;
+const IRQ_SYNC_DETECTED: u16 = 1 << 3;
+const IRQ_HEADER_ERR: u16 = 1 << 5;
+const IRQ_ERR: u16 = 1 << 6;
+const IRQ_CAD_DONE: u16 = 1 << 7;
+const IRQ_CAD_DETECTED: u16 = 1 << 8;
+const IRQ_TIMEOUT: u16 = 1 << 9;
+
+/// What the radio is busy with right now, along with the parameters it was started with
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum RadioMode {
+    /// Settings survive a warm start sleep, a cold start one leaves the radio as if it got reset
+    Sleep {
+        warm: bool,
+    },
+    Standby,
+    Rx {
+        timeout_ms: u32,
+    },
+    Tx {
+        len: u8,
+        timeout_ms: u32,
+    },
+    Cad,
+}
+
+impl RadioMode {
+    /// 1 byte version for status reports, parameters left out
+    pub fn code(&self) -> u8 {
+        match self {
+            RadioMode::Sleep { warm: false } => 0,
+            RadioMode::Sleep { warm: true } => 1,
+            RadioMode::Standby => 2,
+            RadioMode::Rx { .. } => 3,
+            RadioMode::Tx { .. } => 4,
+            RadioMode::Cad => 5,
+        }
+    }
+}
+
+/// IRQ status interpreted against the mode the radio was in when it fired
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum RadioEvent {
+    TxDone,
+    TxTimeout,
+    RxDone,
+    RxTimeout,
+    /// Reception aborted by a bad LoRa header or a CRC mismatch, the packet is not worth reading
+    RxError {
+        header: bool,
+        crc: bool,
+    },
+    /// Preamble or sync word seen, the radio is still receiving
+    RxDetected {
+        preamble: bool,
+        sync: bool,
+    },
+    CadDone {
+        detected: bool,
+    },
+    Unexpected(u16),
+}
+
+/// Mode change refused by `RadioState::check`, ends up as `RadioError::IllegalTransition`
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub struct IllegalTransition {
+    pub from: RadioMode,
+    pub to: RadioMode,
+}
+
+/// Tracks the radio mode so that IRQs can be told apart and commands can't stomp on an ongoing Tx.
+/// Pure bookkeeping, no SPI access in here.
+pub struct RadioState {
+    mode: RadioMode,
+    op_count: u32,
+    last_rx: Option<(i16, i16)>,
+}
+
+impl Default for RadioState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RadioState {
+    /// `setup_radio` leaves the radio in standby
+    pub const fn new() -> RadioState {
+        RadioState {
+            mode: RadioMode::Standby,
+            op_count: 0,
+            last_rx: None,
+        }
+    }
+
+    pub fn mode(&self) -> RadioMode {
+        self.mode
+    }
+
+    /// Bumped on every mode change (including re-entering the same mode), tells operations apart
+    pub fn op_count(&self) -> u32 {
+        self.op_count
+    }
+
+    /// Woken up from a cold start sleep the radio has lost everything the host configured
+    pub fn config_lost(&self) -> bool {
+        matches!(self.mode, RadioMode::Sleep { warm: false })
+    }
+
+    /// Tx and CAD have to finish (or get aborted) before anything else can be started
+    pub fn is_busy(&self) -> bool {
+        matches!(self.mode, RadioMode::Tx { .. } | RadioMode::Cad)
+    }
+
+    pub fn check(&self, next: RadioMode) -> Result<(), IllegalTransition> {
+        // Standby or sleep can always be forced, that's how an ongoing operation gets aborted
+        let legal = matches!(next, RadioMode::Sleep { .. } | RadioMode::Standby) || !self.is_busy();
+        if legal {
+            Ok(())
+        } else {
+            defmt::warn!("RadioState: illegal transition {:?} -> {:?}", self.mode, next);
+            Err(IllegalTransition {
+                from: self.mode,
+                to: next,
+            })
+        }
+    }
+
+    /// Changing modulation/PHY settings puts the radio to standby, which would cut off Tx or CAD
+    pub fn check_configure(&self) -> Result<(), IllegalTransition> {
+        if self.is_busy() {
+            defmt::warn!("RadioState: can't configure while in {:?}", self.mode);
+            return Err(IllegalTransition {
+                from: self.mode,
+                to: RadioMode::Standby,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Keep the RSSI (dBm) and SNR (dB) of the last packet around for status reports
+    pub fn record_rx(&mut self, rssi: i16, snr: i16) {
+        self.last_rx = Some((rssi, snr));
+    }
+
+    /// RSSI in dBm and SNR in dB of the last packet received, if any
+    pub fn last_rx(&self) -> Option<(i16, i16)> {
+        self.last_rx
+    }
+
+    /// Record the mode after the radio accepted the command, see `check` for what's allowed
+    pub fn set(&mut self, mode: RadioMode) {
+        defmt::trace!("RadioState: {:?} -> {:?}", self.mode, mode);
+        self.mode = mode;
+        self.op_count = self.op_count.wrapping_add(1);
+    }
+
+    /// Work out what the IRQ status means for the current mode.
+    /// Tx, Rx and CAD all fall back to standby once they're done, failed or timed out,
+    /// except continuous Rx: that one stays in Rx after a packet, good or bad.
+    pub fn handle_irq(&mut self, irq: u16) -> RadioEvent {
+        let has = |mask: u16| irq & mask != 0;
+        let event = match self.mode {
+            RadioMode::Tx { .. } if has(IRQ_TX_DONE) => RadioEvent::TxDone,
+            RadioMode::Tx { .. } if has(IRQ_TIMEOUT) => RadioEvent::TxTimeout,
+            // CRC error comes together with RxDone, so check errors first
+            RadioMode::Rx { .. } if has(IRQ_HEADER_ERR) || has(IRQ_ERR) => RadioEvent::RxError {
+                header: has(IRQ_HEADER_ERR),
+                crc: has(IRQ_ERR),
+            },
+            RadioMode::Rx { .. } if has(IRQ_RX_DONE) => RadioEvent::RxDone,
+            RadioMode::Rx { .. } if has(IRQ_TIMEOUT) => RadioEvent::RxTimeout,
+            RadioMode::Rx { .. } if has(IRQ_PREAMBLE_DETECTED) || has(IRQ_SYNC_DETECTED) => {
+                return RadioEvent::RxDetected {
+                    preamble: has(IRQ_PREAMBLE_DETECTED),
+                    sync: has(IRQ_SYNC_DETECTED),
+                };
+            }
+            RadioMode::Cad if has(IRQ_CAD_DONE) => RadioEvent::CadDone {
+                detected: has(IRQ_CAD_DETECTED),
+            },
+            _ => return RadioEvent::Unexpected(irq),
+        };
+
+        if !matches!(
+            self.mode,
+            RadioMode::Rx {
+                timeout_ms: 0 | u32::MAX
+            }
+        ) {
+            self.set(RadioMode::Standby);
+        }
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TX: RadioMode = RadioMode::Tx {
+        len: 8,
+        timeout_ms: 5000,
+    };
+    const RX: RadioMode = RadioMode::Rx { timeout_ms: 5000 };
+    const RX_CONTINUOUS: RadioMode = RadioMode::Rx { timeout_ms: 0 };
+
+    fn state_in(mode: RadioMode) -> RadioState {
+        let mut state = RadioState::new();
+        state.set(mode);
+        state
+    }
+
+    #[test]
+    fn anything_goes_from_standby() {
+        let state = RadioState::new();
+        for next in [
+            TX,
+            RX,
+            RadioMode::Cad,
+            RadioMode::Standby,
+            RadioMode::Sleep { warm: true },
+        ] {
+            assert_eq!(state.check(next), Ok(()));
+        }
+        assert_eq!(state.check_configure(), Ok(()));
+    }
+
+    #[test]
+    fn rx_can_be_replaced() {
+        let state = state_in(RX);
+        assert_eq!(state.check(TX), Ok(()));
+        assert_eq!(state.check(RX_CONTINUOUS), Ok(()));
+        assert_eq!(state.check_configure(), Ok(()));
+    }
+
+    #[test]
+    fn busy_modes_only_give_way_to_standby_or_sleep() {
+        for busy in [TX, RadioMode::Cad] {
+            let state = state_in(busy);
+            assert_eq!(state.check(RX), Err(IllegalTransition { from: busy, to: RX }));
+            assert_eq!(state.check(TX), Err(IllegalTransition { from: busy, to: TX }));
+            assert_eq!(
+                state.check_configure(),
+                Err(IllegalTransition {
+                    from: busy,
+                    to: RadioMode::Standby
+                })
+            );
+            assert_eq!(state.check(RadioMode::Standby), Ok(()));
+            assert_eq!(state.check(RadioMode::Sleep { warm: false }), Ok(()));
+        }
+    }
+
+    #[test]
+    fn set_bumps_op_count() {
+        let mut state = RadioState::new();
+        state.set(RX);
+        state.set(RX);
+        assert_eq!(state.op_count(), 2);
+        assert_eq!(state.mode(), RX);
+    }
+
+    #[test]
+    fn cold_sleep_loses_config() {
+        assert!(state_in(RadioMode::Sleep { warm: false }).config_lost());
+        assert!(!state_in(RadioMode::Sleep { warm: true }).config_lost());
+        assert!(!RadioState::new().config_lost());
+    }
+
+    #[test]
+    fn tx_irqs() {
+        let mut state = state_in(TX);
+        assert_eq!(state.handle_irq(IRQ_TX_DONE), RadioEvent::TxDone);
+        assert_eq!(state.mode(), RadioMode::Standby);
+
+        let mut state = state_in(TX);
+        assert_eq!(state.handle_irq(IRQ_TIMEOUT), RadioEvent::TxTimeout);
+        assert_eq!(state.mode(), RadioMode::Standby);
+    }
+
+    #[test]
+    fn timed_rx_irqs() {
+        let mut state = state_in(RX);
+        assert_eq!(state.handle_irq(IRQ_RX_DONE), RadioEvent::RxDone);
+        assert_eq!(state.mode(), RadioMode::Standby);
+
+        let mut state = state_in(RX);
+        assert_eq!(state.handle_irq(IRQ_TIMEOUT), RadioEvent::RxTimeout);
+        assert_eq!(state.mode(), RadioMode::Standby);
+    }
+
+    #[test]
+    fn rx_errors_win_over_rx_done() {
+        let mut state = state_in(RX);
+        assert_eq!(
+            state.handle_irq(IRQ_RX_DONE | IRQ_ERR),
+            RadioEvent::RxError {
+                header: false,
+                crc: true
+            }
+        );
+        assert_eq!(state.mode(), RadioMode::Standby);
+
+        let mut state = state_in(RX);
+        assert_eq!(
+            state.handle_irq(IRQ_HEADER_ERR),
+            RadioEvent::RxError {
+                header: true,
+                crc: false
+            }
+        );
+    }
+
+    #[test]
+    fn rx_detected_keeps_receiving() {
+        let mut state = state_in(RX);
+        let op_count = state.op_count();
+        assert_eq!(
+            state.handle_irq(IRQ_PREAMBLE_DETECTED | IRQ_SYNC_DETECTED),
+            RadioEvent::RxDetected {
+                preamble: true,
+                sync: true
+            }
+        );
+        assert_eq!(state.mode(), RX);
+        assert_eq!(state.op_count(), op_count);
+    }
+
+    #[test]
+    fn continuous_rx_stays_in_rx() {
+        for continuous in [RX_CONTINUOUS, RadioMode::Rx { timeout_ms: u32::MAX }] {
+            let mut state = state_in(continuous);
+            assert_eq!(state.handle_irq(IRQ_RX_DONE), RadioEvent::RxDone);
+            assert_eq!(state.mode(), continuous);
+            assert_eq!(
+                state.handle_irq(IRQ_RX_DONE | IRQ_ERR),
+                RadioEvent::RxError {
+                    header: false,
+                    crc: true
+                }
+            );
+            assert_eq!(state.mode(), continuous);
+        }
+    }
+
+    #[test]
+    fn cad_irqs() {
+        let mut state = state_in(RadioMode::Cad);
+        assert_eq!(
+            state.handle_irq(IRQ_CAD_DONE | IRQ_CAD_DETECTED),
+            RadioEvent::CadDone { detected: true }
+        );
+        assert_eq!(state.mode(), RadioMode::Standby);
+    }
+
+    #[test]
+    fn irqs_not_matching_the_mode_are_unexpected() {
+        let cases = [
+            (RadioMode::Standby, IRQ_TX_DONE),
+            (RX, IRQ_TX_DONE),
+            (TX, IRQ_RX_DONE),
+            (RadioMode::Cad, IRQ_TIMEOUT),
+            (RadioMode::Sleep { warm: true }, IRQ_RX_DONE),
+            (TX, 0),
+        ];
+        for (mode, irq) in cases {
+            let mut state = state_in(mode);
+            assert_eq!(state.handle_irq(irq), RadioEvent::Unexpected(irq));
+            assert_eq!(state.mode(), mode);
+        }
+    }
+}
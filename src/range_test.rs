@@ -0,0 +1,252 @@
+use stm32wlxx_hal::subghz::LoRaPacketStatus;
+
+use crate::{
+    constants::CacheQueue,
+    packet::{uart_pkt_encoder::UartPacketEncoder, UartPacketType},
+};
+
+// On-air frame layout:
+//   Ping: 'L' 'R' 0x01 seq(u16 LE)
+//   Echo: 'L' 'R' 0x02 seq(u16 LE) rssi(i16 LE) snr(i16 LE), RSSI/SNR measured by the responder
+const RANGE_TEST_MAGIC: [u8; 2] = *b"LR";
+const RANGE_TEST_PING: u8 = 0x01;
+const RANGE_TEST_ECHO: u8 = 0x02;
+pub const RANGE_TEST_PING_LEN: usize = 5;
+pub const RANGE_TEST_ECHO_LEN: usize = 9;
+pub const RANGE_TEST_RECORD_LEN: usize = 15;
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum RangeTestRole {
+    Initiator = 0,
+    Responder = 1,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum RangeTestState {
+    Idle,
+    InitiatorTx,       // Ping is on air
+    InitiatorWaitEcho, // Waiting for the peer to echo back
+    Responder,         // Listening for pings
+    ResponderTx,       // Echo is on air
+}
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum RangeTestOutcome {
+    Ok = 0,
+    EchoTimeout = 1,
+    TxTimeout = 2,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub struct RangeTestRecord {
+    seq: u16,
+    outcome: RangeTestOutcome,
+    rtt_ms: u32,
+    local_rssi: i16,
+    local_snr: i16,
+    remote_rssi: i16,
+    remote_snr: i16,
+}
+
+impl RangeTestRecord {
+    fn lost(seq: u16, outcome: RangeTestOutcome) -> RangeTestRecord {
+        RangeTestRecord {
+            seq,
+            outcome,
+            rtt_ms: 0,
+            local_rssi: 0,
+            local_snr: 0,
+            remote_rssi: 0,
+            remote_snr: 0,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; RANGE_TEST_RECORD_LEN] {
+        let mut buf: [u8; RANGE_TEST_RECORD_LEN] = [0; RANGE_TEST_RECORD_LEN];
+        buf[0..=1].copy_from_slice(&self.seq.to_le_bytes());
+        buf[2] = self.outcome as u8;
+        buf[3..=6].copy_from_slice(&self.rtt_ms.to_le_bytes());
+        buf[7..=8].copy_from_slice(&self.local_rssi.to_le_bytes());
+        buf[9..=10].copy_from_slice(&self.local_snr.to_le_bytes());
+        buf[11..=12].copy_from_slice(&self.remote_rssi.to_le_bytes());
+        buf[13..=14].copy_from_slice(&self.remote_snr.to_le_bytes());
+        buf
+    }
+
+    pub fn encode(&self, queue: &mut CacheQueue) {
+        let mut encoder = UartPacketEncoder::new(UartPacketType::RangeTestRecord, queue);
+        encoder.add_payload(&self.to_bytes());
+        encoder.finalize();
+    }
+}
+
+pub enum RangeTestRx {
+    NotRangeTest,            // Not ours, forward to host as usual
+    Ignored,                 // Ours but stale or unexpected, just re-arm Rx
+    SendEcho(usize),         // Responder: echo frame of this length is ready in the output buffer
+    Record(RangeTestRecord), // Initiator: got the echo back
+}
+
+pub struct RangeTest {
+    state: RangeTestState,
+    seq: u16,
+    count: u16,
+    echo_timeout_ms: u32,
+    sent_at_ms: u32,
+}
+
+impl Default for RangeTest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RangeTest {
+    pub const fn new() -> RangeTest {
+        RangeTest {
+            state: RangeTestState::Idle,
+            seq: 0,
+            count: 0,
+            echo_timeout_ms: 0,
+            sent_at_ms: 0,
+        }
+    }
+
+    pub fn start(&mut self, role: RangeTestRole, count: u16, echo_timeout_ms: u32) {
+        defmt::info!(
+            "RangeTest: start as {:?}, count={}, echo_timeout={}ms",
+            role,
+            count,
+            echo_timeout_ms
+        );
+        self.seq = 0;
+        self.count = count;
+        self.echo_timeout_ms = echo_timeout_ms;
+        self.state = match role {
+            RangeTestRole::Initiator => RangeTestState::InitiatorTx,
+            RangeTestRole::Responder => RangeTestState::Responder,
+        };
+    }
+
+    pub fn stop(&mut self) {
+        defmt::info!("RangeTest: stopped at seq={}", self.seq);
+        self.state = RangeTestState::Idle;
+    }
+
+    pub fn state(&self) -> RangeTestState {
+        self.state
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state != RangeTestState::Idle
+    }
+
+    /// Build the next ping into `out`, returns `None` and goes idle once all pings are sent
+    pub fn next_ping(&mut self, now_ms: u32, out: &mut [u8]) -> Option<usize> {
+        if !matches!(
+            self.state,
+            RangeTestState::InitiatorTx | RangeTestState::InitiatorWaitEcho
+        ) {
+            return None;
+        }
+
+        if self.seq >= self.count {
+            defmt::info!("RangeTest: all {} pings done", self.count);
+            self.state = RangeTestState::Idle;
+            return None;
+        }
+
+        out[0..=1].copy_from_slice(&RANGE_TEST_MAGIC);
+        out[2] = RANGE_TEST_PING;
+        out[3..=4].copy_from_slice(&self.seq.to_le_bytes());
+
+        self.sent_at_ms = now_ms;
+        self.state = RangeTestState::InitiatorTx;
+        Some(RANGE_TEST_PING_LEN)
+    }
+
+    pub fn handle_tx_done(&mut self) {
+        match self.state {
+            RangeTestState::InitiatorTx => self.state = RangeTestState::InitiatorWaitEcho,
+            RangeTestState::ResponderTx => self.state = RangeTestState::Responder,
+            _ => {}
+        }
+    }
+
+    /// Rx timeout the range test wants the radio re-armed with, `None` if it doesn't care
+    pub fn rx_timeout(&self) -> Option<u32> {
+        match self.state {
+            RangeTestState::InitiatorWaitEcho => Some(self.echo_timeout_ms),
+            RangeTestState::Responder => Some(0),
+            _ => None,
+        }
+    }
+
+    /// Returns the record for the ping currently in flight if it got lost, then moves onto the next sequence
+    pub fn handle_timeout(&mut self) -> Option<RangeTestRecord> {
+        let outcome = match self.state {
+            RangeTestState::InitiatorWaitEcho => RangeTestOutcome::EchoTimeout,
+            RangeTestState::InitiatorTx => RangeTestOutcome::TxTimeout,
+            RangeTestState::ResponderTx => {
+                self.state = RangeTestState::Responder;
+                return None;
+            }
+            _ => return None,
+        };
+
+        let record = RangeTestRecord::lost(self.seq, outcome);
+        self.seq = self.seq.wrapping_add(1);
+        Some(record)
+    }
+
+    pub fn handle_rx(
+        &mut self,
+        payload: &[u8],
+        pkt_status: &LoRaPacketStatus,
+        now_ms: u32,
+        out: &mut [u8],
+    ) -> RangeTestRx {
+        if !self.is_active() || payload.len() < RANGE_TEST_PING_LEN || payload[0..=1] != RANGE_TEST_MAGIC {
+            return RangeTestRx::NotRangeTest;
+        }
+
+        let seq = u16::from_le_bytes(payload[3..=4].try_into().unwrap());
+        let rssi = pkt_status.signal_rssi_pkt().to_integer();
+        let snr = pkt_status.snr_pkt().to_integer();
+
+        match (self.state, payload[2]) {
+            (RangeTestState::Responder, RANGE_TEST_PING) => {
+                out[0..=1].copy_from_slice(&RANGE_TEST_MAGIC);
+                out[2] = RANGE_TEST_ECHO;
+                out[3..=4].copy_from_slice(&seq.to_le_bytes());
+                out[5..=6].copy_from_slice(&rssi.to_le_bytes());
+                out[7..=8].copy_from_slice(&snr.to_le_bytes());
+
+                self.state = RangeTestState::ResponderTx;
+                RangeTestRx::SendEcho(RANGE_TEST_ECHO_LEN)
+            }
+            (RangeTestState::InitiatorWaitEcho, RANGE_TEST_ECHO) if payload.len() >= RANGE_TEST_ECHO_LEN => {
+                if seq != self.seq {
+                    defmt::warn!("RangeTest: stale echo seq={}, expecting {}", seq, self.seq);
+                    return RangeTestRx::Ignored;
+                }
+
+                let record = RangeTestRecord {
+                    seq,
+                    outcome: RangeTestOutcome::Ok,
+                    rtt_ms: now_ms.wrapping_sub(self.sent_at_ms),
+                    local_rssi: rssi,
+                    local_snr: snr,
+                    remote_rssi: i16::from_le_bytes(payload[5..=6].try_into().unwrap()),
+                    remote_snr: i16::from_le_bytes(payload[7..=8].try_into().unwrap()),
+                };
+
+                self.seq = self.seq.wrapping_add(1);
+                RangeTestRx::Record(record)
+            }
+            _ => RangeTestRx::Ignored,
+        }
+    }
+}
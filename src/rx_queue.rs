@@ -0,0 +1,119 @@
+use heapless::Deque;
+use stm32wlxx_hal::subghz::LoRaPacketStatus;
+
+use crate::{
+    constants::CacheQueue,
+    packet::{free_space, max_encoded_len, uart_pkt_encoder::UartPacketEncoder, UartPacketType},
+    radio::encode_radio_packet,
+    stats::{self, Stat},
+};
+
+pub const RX_QUEUE_DEPTH: usize = 4;
+
+const MAX_RX_FRAME_LEN: usize = max_encoded_len(4 + 255); // RSSI & SNR plus the radio payload
+const MAX_DROP_FRAME_LEN: usize = max_encoded_len(4);
+
+struct RxPacket {
+    len: usize,
+    buf: [u8; 255],
+    pkt_status: LoRaPacketStatus,
+}
+
+/// Received radio packets waiting for room in the UART Tx queue.
+/// When full the oldest packet gets dropped as a whole, so the host never sees half a frame.
+pub struct RxPacketQueue {
+    packets: Deque<RxPacket, RX_QUEUE_DEPTH>,
+    dropped: u32,
+    dropped_total: u32,
+    paused: bool,
+}
+
+impl Default for RxPacketQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RxPacketQueue {
+    pub const fn new() -> RxPacketQueue {
+        RxPacketQueue {
+            packets: Deque::new(),
+            dropped: 0,
+            dropped_total: 0,
+            paused: false,
+        }
+    }
+
+    pub fn push(&mut self, payload: &[u8], pkt_status: LoRaPacketStatus) {
+        let mut packet = RxPacket {
+            len: payload.len(),
+            buf: [0; 255],
+            pkt_status,
+        };
+        packet.buf[0..payload.len()].copy_from_slice(payload);
+
+        if self.packets.is_full() {
+            self.packets.pop_front();
+            self.dropped = self.dropped.saturating_add(1);
+            self.dropped_total = self.dropped_total.saturating_add(1);
+            stats::count(Stat::RxQueueDropped);
+            defmt::warn!(
+                "RxPacketQueue: full, dropped oldest; total dropped={}",
+                self.dropped_total
+            );
+        }
+
+        // Can't fail since we just made room
+        let _ = self.packets.push_back(packet);
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.packets.is_empty() || self.dropped > 0
+    }
+
+    pub fn dropped_total(&self) -> u32 {
+        self.dropped_total
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Host fell behind and asked to stop forwarding for now, packets keep piling up here meanwhile
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Move as many whole frames as the UART Tx queue can take, returns true if anything got moved
+    pub fn flush_into(&mut self, uart_tx_q: &mut CacheQueue) -> bool {
+        let mut moved = false;
+        if self.paused {
+            return moved;
+        }
+
+        if self.dropped > 0 && free_space(uart_tx_q) >= MAX_DROP_FRAME_LEN {
+            let mut encoder = UartPacketEncoder::new(UartPacketType::RadioRxDropped, uart_tx_q);
+            encoder.add_payload(&self.dropped.to_le_bytes());
+            encoder.finalize();
+            self.dropped = 0;
+            moved = true;
+        }
+
+        while !self.packets.is_empty() && self.dropped == 0 && free_space(uart_tx_q) >= MAX_RX_FRAME_LEN {
+            if let Some(packet) = self.packets.pop_front() {
+                encode_radio_packet(&packet.buf[0..packet.len], packet.pkt_status, uart_tx_q);
+                moved = true;
+            }
+        }
+
+        moved
+    }
+}
@@ -0,0 +1,291 @@
+use core::ptr;
+
+use stm32wlxx_hal::{
+    flash::{self, AlignedAddr, Flash, Page},
+    pac::Peripherals,
+    pwr::{enter_lprun_msi, exit_lprun, LprunRange},
+    spi::{SgMiso, SgMosi},
+    subghz::SubGhz,
+};
+
+use crate::{
+    error::Error,
+    packet::{
+        radio_bpsk_cfg::RadioBpskConfigurator, radio_freq_cfg::RadioFreqConfigurator,
+        radio_gfsk_cfg::RadioGfskConfigurator, radio_lora_cfg::RadioLoraConfigurator,
+        radio_phy_cfg::RadioPhyConfigurator, uart_pkt_decoder::UartPacketDecoder, UartPacketType, CRC,
+    },
+    radio::PacketParams,
+    radio_cfg::{ActiveModulation, ActiveRadioConfig},
+    uart_baud::SUPPORTED_BAUDS,
+};
+
+// Must match the SETTINGS region in memory.x
+const SETTINGS_FIRST_PAGE: u8 = 124;
+const SETTINGS_PAGE_COUNT: usize = 4;
+const SETTINGS_BASE: usize = 0x0803_e000;
+const PAGE_SIZE: usize = 2048;
+
+// Records are appended one after another and wrap around the pages, newest sequence number wins
+const RECORD_LEN: usize = 64;
+const RECORDS_PER_PAGE: usize = PAGE_SIZE / RECORD_LEN;
+const RECORD_SLOTS: usize = RECORDS_PER_PAGE * SETTINGS_PAGE_COUNT;
+const RECORD_MAGIC: u32 = 0x4653_504c; // "LPSF"
+
+const OFF_MAGIC: usize = 0;
+const OFF_SEQ: usize = 4;
+const OFF_FLAGS: usize = 8;
+const OFF_MOD_TYPE: usize = 9;
+const OFF_MOD_LEN: usize = 10;
+const OFF_BAUD: usize = 11; // Index into SUPPORTED_BAUDS, 0xff for the default
+const OFF_AUTO_RX: usize = 12;
+const OFF_PHY: usize = 16;
+const OFF_FREQ: usize = 22;
+const OFF_MOD: usize = 26;
+const OFF_CRC: usize = RECORD_LEN - 2;
+
+const FLAG_PHY: u8 = 1 << 0;
+const FLAG_FREQ: u8 = 1 << 1;
+const FLAG_AUTO_RX: u8 = 1 << 2;
+
+/// Radio configuration as stored in flash, kept in the same wire format the set commands use
+pub struct SettingsRecord {
+    buf: [u8; RECORD_LEN],
+}
+
+impl SettingsRecord {
+    pub fn new(active: &ActiveRadioConfig, auto_rx_ms: Option<u32>) -> SettingsRecord {
+        let mut buf: [u8; RECORD_LEN] = [0xff; RECORD_LEN];
+        let mut flags: u8 = 0;
+
+        if let Some(phy) = active.phy() {
+            buf[OFF_PHY..OFF_FREQ].copy_from_slice(phy.as_bytes());
+            flags |= FLAG_PHY;
+        }
+
+        if let Some(freq) = active.freq() {
+            buf[OFF_FREQ..OFF_MOD].copy_from_slice(freq.as_bytes());
+            flags |= FLAG_FREQ;
+        }
+
+        match active.modulation_bytes() {
+            Some((pkt_type, bytes)) => {
+                buf[OFF_MOD_TYPE] = pkt_type as u8;
+                buf[OFF_MOD_LEN] = bytes.len() as u8;
+                buf[OFF_MOD..(OFF_MOD + bytes.len())].copy_from_slice(bytes);
+            }
+            None => {
+                buf[OFF_MOD_TYPE] = 0;
+                buf[OFF_MOD_LEN] = 0;
+            }
+        }
+
+        if let Some(timeout_ms) = auto_rx_ms {
+            buf[OFF_AUTO_RX..OFF_PHY].copy_from_slice(&timeout_ms.to_le_bytes());
+            flags |= FLAG_AUTO_RX;
+        }
+
+        buf[OFF_FLAGS] = flags;
+        buf[OFF_MAGIC..OFF_SEQ].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        SettingsRecord { buf }
+    }
+
+    /// Nothing but the UART rate, the radio stays on the compiled-in defaults at boot
+    pub fn baud_only(baud: u32) -> SettingsRecord {
+        let mut buf: [u8; RECORD_LEN] = [0xff; RECORD_LEN];
+        buf[OFF_FLAGS] = 0;
+        buf[OFF_MOD_TYPE] = 0;
+        buf[OFF_MOD_LEN] = 0;
+        buf[OFF_MAGIC..OFF_SEQ].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+
+        let mut record = SettingsRecord { buf };
+        record.set_baud(Some(baud));
+        record
+    }
+
+    fn from_slot(slot: usize) -> Option<SettingsRecord> {
+        let buf: [u8; RECORD_LEN] = unsafe { ptr::read_volatile(slot_addr(slot) as *const [u8; RECORD_LEN]) };
+        let record = SettingsRecord { buf };
+
+        let magic = u32::from_le_bytes(buf[OFF_MAGIC..OFF_SEQ].try_into().unwrap());
+        let crc = u16::from_le_bytes(buf[OFF_CRC..].try_into().unwrap());
+        if magic != RECORD_MAGIC || CRC.checksum(&buf[0..OFF_CRC]) != crc {
+            return None;
+        }
+
+        Some(record)
+    }
+
+    fn seal(&mut self, seq: u32) {
+        self.buf[OFF_SEQ..OFF_FLAGS].copy_from_slice(&seq.to_le_bytes());
+        let crc = CRC.checksum(&self.buf[0..OFF_CRC]);
+        self.buf[OFF_CRC..].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn seq(&self) -> u32 {
+        u32::from_le_bytes(self.buf[OFF_SEQ..OFF_FLAGS].try_into().unwrap())
+    }
+
+    /// Rx timeout to start with after applying the settings at boot, if the host asked for it
+    pub fn auto_rx_ms(&self) -> Option<u32> {
+        if self.buf[OFF_FLAGS] & FLAG_AUTO_RX == 0 {
+            return None;
+        }
+
+        Some(u32::from_le_bytes(self.buf[OFF_AUTO_RX..OFF_PHY].try_into().unwrap()))
+    }
+
+    /// UART rate to switch to at boot, if the host saved one
+    pub fn baud(&self) -> Option<u32> {
+        SUPPORTED_BAUDS.get(self.buf[OFF_BAUD] as usize).copied()
+    }
+
+    pub fn set_baud(&mut self, baud: Option<u32>) {
+        self.buf[OFF_BAUD] = baud
+            .and_then(|baud| SUPPORTED_BAUDS.iter().position(|b| *b == baud))
+            .map(|idx| idx as u8)
+            .unwrap_or(0xff);
+    }
+
+    /// Configure the radio the same way as if the host sent Phy, Freq and modulation config again
+    pub fn apply(
+        &self,
+        radio: &mut SubGhz<SgMiso, SgMosi>,
+        active: &mut ActiveRadioConfig,
+        pkt_params: &mut Option<PacketParams>,
+    ) -> Result<(), Error> {
+        let flags = self.buf[OFF_FLAGS];
+
+        if flags & FLAG_PHY != 0 {
+            let cfg = RadioPhyConfigurator::try_from(self.decoder(UartPacketType::RadioPhyConfig, OFF_PHY, OFF_FREQ))?;
+            cfg.configure_radio(radio)?;
+            active.set_phy(cfg);
+        }
+
+        if flags & FLAG_FREQ != 0 {
+            let cfg =
+                RadioFreqConfigurator::try_from(self.decoder(UartPacketType::RadioFreqConfig, OFF_FREQ, OFF_MOD))?;
+            cfg.configure_radio(radio)?;
+            active.set_freq(cfg);
+        }
+
+        let mod_end = OFF_MOD + (self.buf[OFF_MOD_LEN] as usize).min(OFF_CRC - OFF_MOD);
+        match UartPacketType::try_from(self.buf[OFF_MOD_TYPE]) {
+            Ok(pkt_type @ UartPacketType::RadioLoraConfig) => {
+                let cfg = RadioLoraConfigurator::try_from(self.decoder(pkt_type, OFF_MOD, mod_end))?;
+                cfg.configure_radio(radio)?;
+                *pkt_params = Some(cfg.packet_params());
+                active.set_modulation(ActiveModulation::LoRa(cfg));
+            }
+            Ok(pkt_type @ UartPacketType::RadioGfskConfig) => {
+                let cfg = RadioGfskConfigurator::try_from(self.decoder(pkt_type, OFF_MOD, mod_end))?;
+                cfg.configure_radio(radio)?;
+                *pkt_params = Some(cfg.packet_params());
+                active.set_modulation(ActiveModulation::Gfsk(cfg));
+            }
+            Ok(pkt_type @ UartPacketType::RadioBpskConfig) => {
+                let cfg = RadioBpskConfigurator::try_from(self.decoder(pkt_type, OFF_MOD, mod_end))?;
+                cfg.configure_radio(radio)?;
+                *pkt_params = Some(cfg.packet_params());
+                active.set_modulation(ActiveModulation::Bpsk(cfg));
+            }
+            _ => {}
+        }
+
+        defmt::info!("SettingsRecord: applied settings #{}, flags=0x{:x}", self.seq(), flags);
+        Ok(())
+    }
+
+    fn decoder(&self, pkt_type: UartPacketType, from: usize, to: usize) -> UartPacketDecoder {
+        UartPacketDecoder::from_payload(pkt_type, &self.buf[from..to])
+    }
+}
+
+fn slot_addr(slot: usize) -> usize {
+    SETTINGS_BASE + slot * RECORD_LEN
+}
+
+fn slot_is_blank(slot: usize) -> bool {
+    let buf: [u8; RECORD_LEN] = unsafe { ptr::read_volatile(slot_addr(slot) as *const [u8; RECORD_LEN]) };
+    buf.iter().all(|b| *b == 0xff)
+}
+
+fn slot_page(slot: usize) -> Page {
+    Page::from_index(SETTINGS_FIRST_PAGE + (slot / RECORDS_PER_PAGE) as u8).unwrap()
+}
+
+fn find_newest() -> Option<(usize, SettingsRecord)> {
+    let mut newest: Option<(usize, SettingsRecord)> = None;
+    for slot in 0..RECORD_SLOTS {
+        if let Some(record) = SettingsRecord::from_slot(slot) {
+            match &newest {
+                Some((_, prev)) if prev.seq() >= record.seq() => {}
+                _ => newest = Some((slot, record)),
+            }
+        }
+    }
+
+    newest
+}
+
+/// Flash can't be programmed or erased in LPRun, so step out of it for the duration
+fn with_flash<T>(f: impl FnOnce(&mut Flash) -> Result<T, flash::Error>) -> Result<T, flash::Error> {
+    let mut dp = unsafe { Peripherals::steal() };
+    exit_lprun(&mut dp.PWR);
+
+    let ret = {
+        let mut flash = Flash::unlock(&mut dp.FLASH);
+        f(&mut flash)
+    };
+
+    cortex_m::interrupt::free(|cs| unsafe {
+        enter_lprun_msi(&mut dp.FLASH, &mut dp.PWR, &mut dp.RCC, LprunRange::Range1M, cs)
+    });
+
+    ret
+}
+
+/// Latest intact record, if anything was ever saved
+pub fn load_settings() -> Option<SettingsRecord> {
+    find_newest().map(|(_, record)| record)
+}
+
+/// Append the record after the newest one, erasing the next page whenever we step into it
+pub fn save_settings(mut record: SettingsRecord) -> Result<(), flash::Error> {
+    let (mut slot, seq) = match find_newest() {
+        Some((slot, prev)) => ((slot + 1) % RECORD_SLOTS, prev.seq().wrapping_add(1)),
+        None => (0, 0),
+    };
+    record.seal(seq);
+
+    with_flash(|flash| {
+        loop {
+            if slot % RECORDS_PER_PAGE == 0 {
+                unsafe { flash.page_erase(slot_page(slot))? };
+                break;
+            }
+
+            // Skip whatever a power loss left half-written
+            if slot_is_blank(slot) {
+                break;
+            }
+
+            slot = (slot + 1) % RECORD_SLOTS;
+        }
+
+        unsafe { flash.program_bytes(&record.buf, AlignedAddr::try_from(slot_addr(slot)).unwrap())? };
+        defmt::info!("save_settings: settings #{} saved to slot {}", seq, slot);
+        Ok(())
+    })
+}
+
+pub fn erase_settings() -> Result<(), flash::Error> {
+    with_flash(|flash| {
+        for page in 0..SETTINGS_PAGE_COUNT {
+            unsafe { flash.page_erase(slot_page(page * RECORDS_PER_PAGE))? };
+        }
+
+        defmt::info!("erase_settings: settings erased");
+        Ok(())
+    })
+}
@@ -0,0 +1,98 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use stm32wlxx_hal::uart;
+
+use crate::{
+    constants::CacheQueue,
+    packet::{uart_pkt_encoder::UartPacketEncoder, UartPacketError, UartPacketType},
+    radio_state::RadioEvent,
+};
+
+/// Index is the position in the `Stats` reply, so only append to this
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum Stat {
+    UartFramesRx = 0,
+    UartFramesTx = 1,
+    DecodeCorrupted = 2,
+    DecodeBufferFull = 3,
+    DecodeEncoding = 4,
+    DecodeUnknownType = 5,
+    UartParityError = 6,
+    UartFramingError = 7,
+    UartNoiseError = 8,
+    UartOverrunError = 9,
+    UartRxOverflow = 10,
+    UartStaleFrame = 11,
+    UartTxBytesDropped = 12,
+    RadioTxOk = 13,
+    RadioTxTimeout = 14,
+    RadioRxOk = 15,
+    RadioRxCrcError = 16,
+    RadioRxHeaderError = 17,
+    RadioRxTimeout = 18,
+    RxQueueDropped = 19,
+    TxQueueRejected = 20,
+    UartTxFramesDropped = 21,
+}
+
+const STAT_COUNT: usize = 22;
+
+// Bumped from every priority level, atomics save taking a lock for each of them
+static COUNTERS: [AtomicU32; STAT_COUNT] = [const { AtomicU32::new(0) }; STAT_COUNT];
+
+pub fn count(stat: Stat) {
+    count_n(stat, 1);
+}
+
+pub fn count_n(stat: Stat, n: u32) {
+    COUNTERS[stat as usize].fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn count_packet_error(err: UartPacketError) {
+    count(match err {
+        UartPacketError::CorruptedError => Stat::DecodeCorrupted,
+        UartPacketError::BufferFullError => Stat::DecodeBufferFull,
+        UartPacketError::EncodingError => Stat::DecodeEncoding,
+        UartPacketError::UnknownPacketError => Stat::DecodeUnknownType,
+    });
+}
+
+pub fn count_uart_error(err: uart::Error) {
+    count(match err {
+        uart::Error::Parity => Stat::UartParityError,
+        uart::Error::Framing => Stat::UartFramingError,
+        uart::Error::Noise => Stat::UartNoiseError,
+        uart::Error::Overrun => Stat::UartOverrunError,
+    });
+}
+
+pub fn count_radio_event(event: &RadioEvent) {
+    match event {
+        RadioEvent::TxDone => count(Stat::RadioTxOk),
+        RadioEvent::TxTimeout => count(Stat::RadioTxTimeout),
+        RadioEvent::RxDone => count(Stat::RadioRxOk),
+        RadioEvent::RxTimeout => count(Stat::RadioRxTimeout),
+        RadioEvent::RxError { header: true, .. } => count(Stat::RadioRxHeaderError),
+        RadioEvent::RxError { .. } => count(Stat::RadioRxCrcError),
+        _ => {}
+    }
+}
+
+pub fn reset_stats() {
+    for counter in COUNTERS.iter() {
+        counter.store(0, Ordering::Relaxed);
+    }
+}
+
+/// One u32 per `Stat` in order, all little endian
+pub fn encode_stats(queue: &mut CacheQueue) {
+    let mut payload: [u8; STAT_COUNT * 4] = [0; STAT_COUNT * 4];
+    for (idx, counter) in COUNTERS.iter().enumerate() {
+        payload[(idx * 4)..(idx * 4 + 4)].copy_from_slice(&counter.load(Ordering::Relaxed).to_le_bytes());
+    }
+
+    let mut encoder = UartPacketEncoder::new(UartPacketType::Stats, queue);
+    encoder.add_payload(&payload);
+    encoder.finalize();
+}
@@ -0,0 +1,247 @@
+use core::ptr;
+
+use stm32wlxx_hal::{adc::Adc, pac};
+
+use crate::{
+    constants::CacheQueue,
+    packet::{uart_pkt_encoder::UartPacketEncoder, UartPacketType, PROTOCOL_VERSION},
+    radio_cfg::ActiveRadioConfig,
+    radio_state::RadioState,
+};
+
+// VREFINT raw reading taken in the factory at VDDA = 3.3V, see the STM32WLE5 datasheet
+const VREFINT_CAL_ADDR: usize = 0x1fff_75aa;
+const VREFINT_CAL_MV: u32 = 3300;
+const ADC_FULL_SCALE: u32 = 4095;
+
+// VBAT goes into the ADC through a divide by 3 bridge
+const VBAT_DIVIDER: u32 = 3;
+
+const NO_RX_YET: i16 = i16::MIN;
+
+// 64-bit unique device ID, see RM0461 "Unique device ID registers"
+const UID64_ADDR: usize = 0x1fff_7580;
+
+const FW_VERSION: [u8; 3] = [
+    parse_version(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_version(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_version(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+// Set by build.rs
+const GIT_HASH: &str = match option_env!("LPLORA_GIT_HASH") {
+    Some(hash) => hash,
+    None => "",
+};
+const GIT_HASH_MAX_LEN: usize = 8;
+
+const fn parse_version(s: &str) -> u8 {
+    match u8::from_str_radix(s, 10) {
+        Ok(v) => v,
+        Err(_) => 0,
+    }
+}
+
+/// What caused the last reset, read from RCC_CSR once at boot
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum ResetCause {
+    Unknown = 0,
+    PowerOnOrBrownOut = 1, // RCC_CSR has one flag for both
+    Pin = 2,
+    Software = 3,
+    IndependentWatchdog = 4,
+    WindowWatchdog = 5,
+    LowPower = 6,
+    OptionByteLoad = 7,
+}
+
+impl ResetCause {
+    /// Flags stick around until cleared, so this clears them for the next reset to be told apart.
+    /// The pin flag is set for pretty much any reset, it only counts if nothing else is.
+    pub fn read_and_clear() -> ResetCause {
+        let rcc = unsafe { &(*pac::RCC::PTR) };
+        let csr = rcc.csr.read();
+
+        let cause = if csr.lpwrrstf().bit_is_set() {
+            ResetCause::LowPower
+        } else if csr.wwdgrstf().bit_is_set() {
+            ResetCause::WindowWatchdog
+        } else if csr.iwdgrstf().bit_is_set() {
+            ResetCause::IndependentWatchdog
+        } else if csr.sftrstf().bit_is_set() {
+            ResetCause::Software
+        } else if csr.borrstf().bit_is_set() {
+            ResetCause::PowerOnOrBrownOut
+        } else if csr.oblrstf().bit_is_set() {
+            ResetCause::OptionByteLoad
+        } else if csr.pinrstf().bit_is_set() {
+            ResetCause::Pin
+        } else {
+            ResetCause::Unknown
+        };
+
+        rcc.csr.modify(|_, w| w.rmvf().set_bit());
+        defmt::info!("ResetCause: {:?}, RCC_CSR=0x{:x}", cause, csr.bits());
+        cause
+    }
+}
+
+pub fn device_uid() -> u64 {
+    unsafe { ptr::read_volatile(UID64_ADDR as *const u64) }
+}
+
+/// Sent once `init` is done so the host can tell the module restarted and push its config again.
+/// 1 byte of reset cause, 1 byte of protocol version, 3 bytes of firmware version (major, minor, patch),
+/// 8 bytes of device UID, then up to 8 ASCII characters of git hash.
+pub fn encode_boot(reset_cause: ResetCause, queue: &mut CacheQueue) {
+    let hash = &GIT_HASH.as_bytes()[0..GIT_HASH.len().min(GIT_HASH_MAX_LEN)];
+
+    let mut payload: [u8; 13 + GIT_HASH_MAX_LEN] = [0; 13 + GIT_HASH_MAX_LEN];
+    payload[0] = reset_cause as u8;
+    payload[1] = PROTOCOL_VERSION;
+    payload[2..5].copy_from_slice(&FW_VERSION);
+    payload[5..13].copy_from_slice(&device_uid().to_le_bytes());
+    payload[13..(13 + hash.len())].copy_from_slice(hash);
+
+    let mut encoder = UartPacketEncoder::new(UartPacketType::Boot, queue);
+    encoder.add_payload(&payload[0..(13 + hash.len())]);
+    encoder.finalize();
+}
+
+/// MCU temperature and supply voltages, the ADC is only powered up for the duration of a reading
+pub struct StatusAdc {
+    adc: Adc,
+}
+
+pub struct AdcReadings {
+    pub temp_c: i16,
+    pub vdd_mv: u16,
+    pub vbat_mv: u16,
+}
+
+impl StatusAdc {
+    /// Takes an ADC that has been calibrated already
+    pub fn new(mut adc: Adc) -> StatusAdc {
+        adc.set_max_sample_time(); // Internal channels need long sampling times
+        StatusAdc { adc }
+    }
+
+    pub fn measure(&mut self) -> AdcReadings {
+        // Sensors get their startup time while the ADC itself is being enabled
+        self.adc.enable_tsen();
+        self.adc.enable_vref();
+        self.adc.enable_vbat();
+        self.adc.enable();
+
+        let temp_c = self.adc.temperature().to_integer();
+        let vref_raw = (self.adc.vref() as u32).max(1);
+        let vbat_raw = self.adc.vbat() as u32;
+
+        self.adc.disable();
+        self.adc.disable_vbat();
+        self.adc.disable_vref();
+        self.adc.disable_tsen();
+
+        let vref_cal = unsafe { ptr::read_volatile(VREFINT_CAL_ADDR as *const u16) } as u32;
+        let vdd_mv = VREFINT_CAL_MV * vref_cal / vref_raw;
+        let vbat_mv = vbat_raw * VBAT_DIVIDER * vdd_mv / ADC_FULL_SCALE;
+
+        AdcReadings {
+            temp_c,
+            vdd_mv: vdd_mv as u16,
+            vbat_mv: vbat_mv as u16,
+        }
+    }
+}
+
+/// Answers `GetStatus` and sends the same frame unsolicited every `interval_ms` if the host asked for that
+pub struct StatusReporter {
+    adc: StatusAdc,
+    reset_cause: ResetCause,
+    interval_ms: Option<u32>,
+    sent_at_ms: u32,
+}
+
+impl StatusReporter {
+    pub fn new(adc: StatusAdc, reset_cause: ResetCause) -> StatusReporter {
+        StatusReporter {
+            adc,
+            reset_cause,
+            interval_ms: None,
+            sent_at_ms: 0,
+        }
+    }
+
+    pub fn reset_cause(&self) -> ResetCause {
+        self.reset_cause
+    }
+
+    /// 0 stops the periodic frames
+    pub fn set_interval(&mut self, interval_ms: u32, now_ms: u32) {
+        self.interval_ms = (interval_ms > 0).then_some(interval_ms);
+        self.sent_at_ms = now_ms;
+    }
+
+    /// 4 bytes of uptime in ms, 1 byte of radio mode, 1 byte of modulation (its set command type, 0 if none),
+    /// 4 bytes of frequency in Hz (0 if none), 2 bytes of last Rx RSSI and 2 bytes of SNR (0x8000 if nothing received yet),
+    /// 2 bytes each of `uart_rx_q` and `uart_tx_q` fill, 2 bytes of temperature in C, 2 bytes each of VDD and VBAT in mV,
+    /// then 1 byte of reset cause. All little endian.
+    pub fn encode(
+        &mut self,
+        now_ms: u32,
+        radio_state: &RadioState,
+        active_cfg: &ActiveRadioConfig,
+        uart_rx_q: &CacheQueue,
+        uart_tx_q: &mut CacheQueue,
+    ) {
+        let readings = self.adc.measure();
+        let modulation = active_cfg
+            .modulation_bytes()
+            .map(|(pkt_type, _)| pkt_type as u8)
+            .unwrap_or(0);
+        let freq_hz = active_cfg.freq().map(|freq| freq.freq_hz()).unwrap_or(0);
+        let (rssi, snr) = radio_state.last_rx().unwrap_or((NO_RX_YET, NO_RX_YET));
+
+        let mut payload: [u8; 25] = [0; 25];
+        payload[0..4].copy_from_slice(&now_ms.to_le_bytes());
+        payload[4] = radio_state.mode().code();
+        payload[5] = modulation;
+        payload[6..10].copy_from_slice(&freq_hz.to_le_bytes());
+        payload[10..12].copy_from_slice(&rssi.to_le_bytes());
+        payload[12..14].copy_from_slice(&snr.to_le_bytes());
+        payload[14..16].copy_from_slice(&(uart_rx_q.len() as u16).to_le_bytes());
+        payload[16..18].copy_from_slice(&(uart_tx_q.len() as u16).to_le_bytes());
+        payload[18..20].copy_from_slice(&readings.temp_c.to_le_bytes());
+        payload[20..22].copy_from_slice(&readings.vdd_mv.to_le_bytes());
+        payload[22..24].copy_from_slice(&readings.vbat_mv.to_le_bytes());
+        payload[24] = self.reset_cause as u8;
+
+        let mut encoder = UartPacketEncoder::new(UartPacketType::Status, uart_tx_q);
+        encoder.add_payload(&payload);
+        encoder.finalize();
+        self.sent_at_ms = now_ms;
+    }
+
+    /// Send one if periodic frames are on and the interval is up, returns true if it did
+    pub fn encode_if_due(
+        &mut self,
+        now_ms: u32,
+        radio_state: &RadioState,
+        active_cfg: &ActiveRadioConfig,
+        uart_rx_q: &CacheQueue,
+        uart_tx_q: &mut CacheQueue,
+    ) -> bool {
+        let interval_ms = match self.interval_ms {
+            Some(ms) => ms,
+            None => return false,
+        };
+
+        if now_ms.wrapping_sub(self.sent_at_ms) < interval_ms {
+            return false;
+        }
+
+        self.encode(now_ms, radio_state, active_cfg, uart_rx_q, uart_tx_q);
+        true
+    }
+}
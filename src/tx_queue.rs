@@ -0,0 +1,167 @@
+use heapless::Deque;
+use stm32wlxx_hal::{
+    spi::{SgMiso, SgMosi},
+    subghz::SubGhz,
+};
+
+use crate::{
+    constants::CacheQueue,
+    packet::{uart_pkt_encoder::UartPacketEncoder, UartPacketType},
+    radio::{start_radio_tx, PacketParams},
+    radio_state::RadioState,
+    stats::{self, Stat},
+};
+
+pub const TX_QUEUE_DEPTH: usize = 8;
+const TX_QUEUE_TIMEOUT_MS: u32 = 5000;
+
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, defmt::Format)]
+pub enum TxStatus {
+    Done = 0,
+    Timeout = 1,
+    Failed = 2,
+}
+
+struct TxQueueEntry {
+    id: u16,
+    len: usize,
+    buf: [u8; 255],
+}
+
+/// Packets queued by `RadioQueueSend`, sent one after another as TxDone arrives.
+/// Plain `RadioSend`/`RadioSendEx` still go straight to the radio, but get refused while a queued packet is on air.
+pub struct TxQueue {
+    pending: Deque<TxQueueEntry, TX_QUEUE_DEPTH>,
+    in_flight: Option<TxQueueEntry>,
+}
+
+impl Default for TxQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxQueue {
+    pub const fn new() -> TxQueue {
+        TxQueue {
+            pending: Deque::new(),
+            in_flight: None,
+        }
+    }
+
+    pub fn push(&mut self, id: u16, payload: &[u8]) -> bool {
+        let mut entry = TxQueueEntry {
+            id,
+            len: payload.len(),
+            buf: [0; 255],
+        };
+        entry.buf[0..payload.len()].copy_from_slice(payload);
+
+        match self.pending.push_back(entry) {
+            Ok(_) => true,
+            Err(_) => {
+                defmt::warn!("TxQueue: full, rejecting id={}", id);
+                stats::count(Stat::TxQueueRejected);
+                false
+            }
+        }
+    }
+
+    pub fn in_flight(&self) -> Option<u16> {
+        self.in_flight.as_ref().map(|entry| entry.id)
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Start sending the next pending packet, packets failing to start get reported and skipped.
+    /// Nothing happens while the radio is still busy with another Tx, it gets picked up after TxDone.
+    /// Returns true if the radio is now transmitting.
+    pub fn start_next(
+        &mut self,
+        radio: &mut SubGhz<SgMiso, SgMosi>,
+        state: &mut RadioState,
+        pkt_params: Option<&PacketParams>,
+        uart_tx_q: &mut CacheQueue,
+    ) -> bool {
+        if state.is_busy() {
+            return false;
+        }
+
+        while let Some(entry) = self.pending.pop_front() {
+            match start_radio_tx(radio, state, pkt_params, &entry.buf[0..entry.len], TX_QUEUE_TIMEOUT_MS) {
+                Ok(_) => {
+                    defmt::info!("TxQueue: sending id={}, len={}", entry.id, entry.len);
+                    self.in_flight = Some(entry);
+                    return true;
+                }
+                Err(_) => {
+                    defmt::error!("TxQueue: failed to start id={}", entry.id);
+                    encode_tx_status(uart_tx_q, entry.id, TxStatus::Failed);
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Send the packet on air again from the start, e.g. after the radio got reset halfway through.
+    /// Returns true if the radio is now transmitting.
+    pub fn retry(
+        &mut self,
+        radio: &mut SubGhz<SgMiso, SgMosi>,
+        state: &mut RadioState,
+        pkt_params: Option<&PacketParams>,
+    ) -> bool {
+        let entry = match &self.in_flight {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        match start_radio_tx(radio, state, pkt_params, &entry.buf[0..entry.len], TX_QUEUE_TIMEOUT_MS) {
+            Ok(_) => {
+                defmt::info!("TxQueue: retrying id={}", entry.id);
+                true
+            }
+            Err(_) => {
+                defmt::error!("TxQueue: failed to retry id={}", entry.id);
+                false
+            }
+        }
+    }
+
+    /// Report the packet on air as finished, returns false if nothing from the queue was on air
+    pub fn finish(&mut self, status: TxStatus, uart_tx_q: &mut CacheQueue) -> bool {
+        match self.in_flight.take().map(|entry| entry.id) {
+            Some(id) => {
+                defmt::info!("TxQueue: id={} finished, {:?}", id, status);
+                encode_tx_status(uart_tx_q, id, status);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Report everything as failed, the one on air and the ones still waiting, and empty the queue.
+    /// For when the host or a radio reset stopped Tx, so nothing queued goes out later by surprise.
+    /// Returns false if the queue was empty already.
+    pub fn fail_all(&mut self, uart_tx_q: &mut CacheQueue) -> bool {
+        let mut failed = self.finish(TxStatus::Failed, uart_tx_q);
+        while let Some(entry) = self.pending.pop_front() {
+            defmt::info!("TxQueue: id={} dropped", entry.id);
+            encode_tx_status(uart_tx_q, entry.id, TxStatus::Failed);
+            failed = true;
+        }
+
+        failed
+    }
+}
+
+fn encode_tx_status(queue: &mut CacheQueue, id: u16, status: TxStatus) {
+    let id_bytes: [u8; 2] = id.to_le_bytes();
+    let mut encoder = UartPacketEncoder::new(UartPacketType::RadioTxStatus, queue);
+    encoder.add_payload(&[id_bytes[0], id_bytes[1], status as u8]);
+    encoder.finalize();
+}
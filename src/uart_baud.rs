@@ -0,0 +1,158 @@
+use stm32wlxx_hal::pac;
+
+pub const DEFAULT_BAUD: u32 = 9600;
+
+/// How long the host gets to send a valid frame at the new rate before we go back to `DEFAULT_BAUD`
+pub const BAUD_FALLBACK_MS: u32 = 3000;
+
+/// Rest of a garbled frame keeps failing after a switch, give it time to pass before trying the next rate
+const HUNT_HOLDOFF_MS: u32 = 100;
+
+/// Index is what gets stored in the settings, so only append to this
+pub const SUPPORTED_BAUDS: [u32; 8] = [9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600];
+
+const LSE_HZ: u32 = 32768;
+const HSI16_HZ: u32 = 16_000_000;
+
+// LPUART needs the kernel clock to be 3 to 4096 times the baud rate
+const LPUART_MIN_CLK_RATIO: u32 = 3;
+
+pub fn is_supported_baud(baud: u32) -> bool {
+    SUPPORTED_BAUDS.contains(&baud)
+}
+
+/// Switch LPUART to another rate, only call this once the Tx shift register is empty.
+/// LSE keeps the lowest power for the default rate, anything faster needs HSI16.
+pub fn set_lpuart_baud(baud: u32) {
+    let use_lse = baud.saturating_mul(LPUART_MIN_CLK_RATIO) <= LSE_HZ;
+    let clk_hz = if use_lse { LSE_HZ } else { HSI16_HZ };
+    let brr = ((256 * clk_hz as u64 + baud as u64 / 2) / baud as u64) as u32;
+
+    unsafe {
+        let rcc = &(*pac::RCC::PTR);
+        let lpuart = &(*pac::LPUART::PTR);
+
+        lpuart.cr1.modify(|_, w| w.ue().clear_bit());
+
+        if use_lse {
+            rcc.ccipr.modify(|_, w| w.lpuart1sel().lse());
+        } else {
+            if rcc.cr.read().hsirdy().bit_is_clear() {
+                rcc.cr.modify(|_, w| w.hsion().set_bit());
+                while rcc.cr.read().hsirdy().bit_is_clear() {}
+            }
+            rcc.ccipr.modify(|_, w| w.lpuart1sel().hsi16());
+        }
+
+        lpuart.brr.write(|w| w.bits(brr));
+        lpuart.cr1.modify(|_, w| w.ue().set_bit());
+    }
+
+    defmt::info!("set_lpuart_baud: now at {}bps, BRR=0x{:x}", baud, brr);
+}
+
+/// Keeps track of a baud rate change until the host proves it can talk at the new rate.
+/// Until the first valid frame (normally a `Ping`) the rate isn't locked yet: every framing error
+/// or garbled frame moves on to the next supported rate, so a host at any of them gets through eventually.
+pub struct BaudState {
+    current: u32,
+    pending: Option<u32>,
+    persist: bool,
+    switched_at_ms: Option<u32>,
+    locked: bool,
+    hunted_at_ms: Option<u32>,
+}
+
+impl BaudState {
+    pub const fn new(current: u32) -> BaudState {
+        BaudState {
+            current,
+            pending: None,
+            persist: false,
+            switched_at_ms: None,
+            locked: false,
+            hunted_at_ms: None,
+        }
+    }
+
+    /// Takes effect once the Ack (at the old rate) is out, see `apply_pending`
+    pub fn request(&mut self, baud: u32, persist: bool) {
+        self.pending = Some(baud);
+        self.persist = persist;
+    }
+
+    /// Switch over if a change is pending, Tx queue must have drained by now
+    pub fn apply_pending(&mut self, now_ms: u32) -> bool {
+        let baud = match self.pending.take() {
+            Some(baud) => baud,
+            None => return false,
+        };
+
+        set_lpuart_baud(baud);
+        self.current = baud;
+        self.switched_at_ms = Some(now_ms);
+        true
+    }
+
+    /// A valid frame came in, so the new rate works. Returns the rate if the host wants it saved.
+    pub fn confirm(&mut self) -> Option<u32> {
+        if !self.locked {
+            defmt::info!("BaudState: locked at {}bps", self.current);
+            self.locked = true;
+        }
+
+        self.switched_at_ms.take()?;
+        defmt::info!("BaudState: {}bps confirmed", self.current);
+
+        if self.persist {
+            self.persist = false;
+            return Some(self.current);
+        }
+
+        None
+    }
+
+    /// Go back to the default rate if nothing valid arrived in time since the switch
+    pub fn check_fallback(&mut self, now_ms: u32) -> bool {
+        let switched_at_ms = match self.switched_at_ms {
+            Some(ms) => ms,
+            None => return false,
+        };
+
+        if now_ms.wrapping_sub(switched_at_ms) < BAUD_FALLBACK_MS {
+            return false;
+        }
+
+        defmt::warn!(
+            "BaudState: no valid frame at {}bps, back to {}bps",
+            self.current,
+            DEFAULT_BAUD
+        );
+        set_lpuart_baud(DEFAULT_BAUD);
+        self.current = DEFAULT_BAUD;
+        self.switched_at_ms = None;
+        self.persist = false;
+        self.locked = false;
+        true
+    }
+
+    /// Garbage came in while the rate isn't locked yet, try the next one.
+    /// Returns false if locked, a garbled frame is just a garbled frame then.
+    pub fn hunt(&mut self, now_ms: u32) -> bool {
+        if self.locked || self.pending.is_some() || self.switched_at_ms.is_some() {
+            return false;
+        }
+
+        if let Some(hunted_at_ms) = self.hunted_at_ms {
+            if now_ms.wrapping_sub(hunted_at_ms) < HUNT_HOLDOFF_MS {
+                return true;
+            }
+        }
+        self.hunted_at_ms = Some(now_ms);
+
+        let idx = SUPPORTED_BAUDS.iter().position(|b| *b == self.current).unwrap_or(0);
+        self.current = SUPPORTED_BAUDS[(idx + 1) % SUPPORTED_BAUDS.len()];
+        set_lpuart_baud(self.current);
+        true
+    }
+}
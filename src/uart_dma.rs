@@ -0,0 +1,273 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use stm32wlxx_hal::pac;
+
+use crate::constants::CacheQueue;
+
+const RX_RING_LEN: usize = 256; // Must divide 2^32, the byte counts below wrap around
+const RX_HALF_LEN: u32 = (RX_RING_LEN / 2) as u32;
+const TX_BLOCK_LEN: usize = 64;
+
+// See RM0461 "DMAMUX: assignment of multiplexer inputs to resources"
+const DMAMUX_REQ_LPUART1_RX: u8 = 21;
+const DMAMUX_REQ_LPUART1_TX: u8 = 22;
+
+const DMA_CCR_EN: u32 = 1 << 0;
+const DMA_CCR_TCIE: u32 = 1 << 1;
+const DMA_CCR_HTIE: u32 = 1 << 2;
+const DMA_CCR_DIR_M2P: u32 = 1 << 4;
+const DMA_CCR_CIRC: u32 = 1 << 5;
+const DMA_CCR_MINC: u32 = 1 << 7;
+
+// Channel 1 is Rx, channel 2 is Tx, each has 4 flag bits (GIF, TCIF, HTIF, TEIF) in ISR/IFCR
+const DMA_CH1_TCIF: u32 = 1 << 1;
+const DMA_CH1_HTIF: u32 = 1 << 2;
+const DMA_CH1_FLAGS: u32 = 0xf;
+const DMA_CH2_TCIF: u32 = 1 << 5;
+const DMA_CH2_FLAGS: u32 = 0xf << 4;
+
+// 0b010 is half full for both Rx and Tx FIFO thresholds
+const LPUART_FIFO_THRESHOLD_HALF: u8 = 0b010;
+
+// LPUART1 RTS and CTS on PA1 and PA6 (both AF8), change these for boards wired differently
+const FLOW_RTS_PIN: u32 = 1;
+const FLOW_CTS_PIN: u32 = 6;
+const GPIO_MODE_AF: u32 = 0b10;
+const GPIO_AF8_LPUART1: u32 = 8;
+
+/// Stop taking bytes from the host once there's less room than this left, leaves some slack for
+/// whatever the host already had on the wire before it saw RTS go away
+const RX_HOLD_MARGIN: usize = 32;
+
+// DMA needs fixed addresses, the resource holding `UartDma` gets moved after init
+static mut RX_RING: [u8; RX_RING_LEN] = [0; RX_RING_LEN];
+static mut TX_BLOCK: [u8; TX_BLOCK_LEN] = [0; TX_BLOCK_LEN];
+
+// Half and full ring IRQs seen so far, each one means another half of the ring got written
+static RX_HALVES: AtomicU32 = AtomicU32::new(0);
+
+/// LPUART in FIFO mode with DMA on both directions: Rx runs forever into a circular buffer,
+/// Tx sends the queue out in blocks. The CPU only gets woken up on idle line, half/full ring and Tx complete.
+pub struct UartDma {
+    rx_read: u32, // Bytes taken off the ring so far, the index is this modulo the ring length
+    tx_busy: bool,
+    rx_held: bool,
+}
+
+impl UartDma {
+    /// Call once LPUART is set up, it gets disabled for a moment since FIFO mode can only be changed then
+    pub fn init() -> UartDma {
+        unsafe {
+            let rcc = &(*pac::RCC::PTR);
+            let dma = &(*pac::DMA1::PTR);
+            let dmamux = &(*pac::DMAMUX::PTR);
+            let lpuart = &(*pac::LPUART::PTR);
+
+            rcc.ahb1enr.modify(|_, w| w.dma1en().set_bit().dmamux1en().set_bit());
+
+            dmamux.c0cr.write(|w| w.dmareq_id().bits(DMAMUX_REQ_LPUART1_RX));
+            dmamux.c1cr.write(|w| w.dmareq_id().bits(DMAMUX_REQ_LPUART1_TX));
+
+            dma.ccr1.write(|w| w.bits(0));
+            dma.cpar1.write(|w| w.bits(lpuart.rdr.as_ptr() as u32));
+            dma.cmar1.write(|w| w.bits(ptr::addr_of_mut!(RX_RING) as u32));
+            dma.cndtr1.write(|w| w.bits(RX_RING_LEN as u32));
+            dma.ccr1
+                .write(|w| w.bits(DMA_CCR_MINC | DMA_CCR_CIRC | DMA_CCR_HTIE | DMA_CCR_TCIE | DMA_CCR_EN));
+
+            dma.ccr2.write(|w| w.bits(0));
+            dma.cpar2.write(|w| w.bits(lpuart.tdr.as_ptr() as u32));
+            dma.cmar2.write(|w| w.bits(ptr::addr_of_mut!(TX_BLOCK) as u32));
+
+            if cfg!(feature = "uart-flow-control") {
+                enable_flow_control_pins();
+            }
+
+            lpuart.cr1.modify(|_, w| w.ue().clear_bit());
+            lpuart.cr1.modify(|_, w| w.fifoen().set_bit());
+            lpuart.cr3.modify(|_, w| {
+                w.dmar()
+                    .set_bit()
+                    .dmat()
+                    .set_bit()
+                    .rxftcfg()
+                    .bits(LPUART_FIFO_THRESHOLD_HALF)
+                    .txftcfg()
+                    .bits(LPUART_FIFO_THRESHOLD_HALF)
+                    .rtse()
+                    .bit(cfg!(feature = "uart-flow-control"))
+                    .ctse()
+                    .bit(cfg!(feature = "uart-flow-control"))
+            });
+            lpuart.cr1.modify(|_, w| w.ue().set_bit());
+        }
+
+        UartDma {
+            rx_read: 0,
+            tx_busy: false,
+            rx_held: false,
+        }
+    }
+
+    fn rx_write_pos(&self) -> usize {
+        let remaining = unsafe { (*pac::DMA1::PTR).cndtr1.read().bits() } as usize;
+        (RX_RING_LEN - remaining) % RX_RING_LEN
+    }
+
+    fn rx_read_pos(&self) -> usize {
+        self.rx_read as usize % RX_RING_LEN
+    }
+
+    /// Bytes the DMA has written so far: the last half boundary the IRQ told us about, plus how far past it the DMA is.
+    /// Still right while the IRQ for the next boundary is pending, as long as it's less than a whole lap behind.
+    fn rx_written(&self) -> u32 {
+        let boundary = RX_HALVES.load(Ordering::Relaxed).wrapping_mul(RX_HALF_LEN);
+        let past = (self.rx_write_pos() + RX_RING_LEN - boundary as usize % RX_RING_LEN) % RX_RING_LEN;
+        boundary.wrapping_add(past as u32)
+    }
+
+    pub fn rx_has_pending(&self) -> bool {
+        self.rx_write_pos() != self.rx_read_pos()
+    }
+
+    pub fn rx_pop(&mut self) -> Option<u8> {
+        if !self.rx_has_pending() {
+            return None;
+        }
+
+        let b = unsafe { ptr::read_volatile((ptr::addr_of!(RX_RING) as *const u8).add(self.rx_read_pos())) };
+        self.rx_read = self.rx_read.wrapping_add(1);
+        Some(b)
+    }
+
+    fn rx_pending_len(&self) -> usize {
+        (self.rx_write_pos() + RX_RING_LEN - self.rx_read_pos()) % RX_RING_LEN
+    }
+
+    /// True if the DMA went a whole lap ahead and overwrote bytes `rx_pop` never got to.
+    /// Whatever is left unread gets skipped, there's a hole somewhere in it.
+    pub fn rx_check_overrun(&mut self) -> bool {
+        let written = self.rx_written();
+        if written.wrapping_sub(self.rx_read) < RX_RING_LEN as u32 {
+            return false;
+        }
+
+        defmt::warn!(
+            "UartDma: Rx ring overrun, {} bytes behind",
+            written.wrapping_sub(self.rx_read)
+        );
+        self.rx_read = written;
+        true
+    }
+
+    /// Hold off the host while either the ring or the frame being collected in `uart_rx_q` is about to run out of room.
+    /// Rx DMA requests get paused, so the LPUART FIFO fills up and deasserts RTS by itself.
+    /// Call after every `rx_pop`, the hold only gets lifted here once the backlog has gone down.
+    pub fn update_rx_hold(&mut self, uart_rx_q: &CacheQueue) {
+        if !cfg!(feature = "uart-flow-control") {
+            return;
+        }
+
+        let pending = self.rx_pending_len();
+        let ring_free = RX_RING_LEN - pending;
+        let frame_free = uart_rx_q.capacity() - uart_rx_q.len();
+
+        // Nothing left to pop means nothing would wake us up to lift the hold again
+        let hold = pending > 0 && (ring_free < RX_HOLD_MARGIN || frame_free < pending + RX_HOLD_MARGIN);
+        if hold == self.rx_held {
+            return;
+        }
+
+        unsafe { (*pac::LPUART::PTR).cr3.modify(|_, w| w.dmar().bit(!hold)) };
+        self.rx_held = hold;
+        defmt::debug!("UartDma: Rx hold {}, {} bytes pending", hold, pending);
+    }
+
+    /// How many more bytes the host may send before it has to wait for the next `Ack`, `Pong` or `FlowStatus`.
+    /// Bytes still sitting in the ring end up in `uart_rx_q` as well, so they're already spoken for.
+    /// Never more than the ring has room for either, or the DMA laps us before `uart_task` gets to run.
+    pub fn rx_credits(&self, uart_rx_q: &CacheQueue) -> u16 {
+        let pending = self.rx_pending_len();
+        let ring_free = RX_RING_LEN - pending;
+        let frame_free = uart_rx_q.capacity() - uart_rx_q.len();
+        frame_free.saturating_sub(pending).min(ring_free).min(u16::MAX as usize) as u16
+    }
+
+    /// Count and clear the Rx half/full ring flags, the bytes themselves get picked up by `rx_pop`
+    pub fn rx_clear_irq() {
+        let dma = unsafe { &(*pac::DMA1::PTR) };
+        let isr = dma.isr.read().bits();
+        let halves = (isr & DMA_CH1_HTIF != 0) as u32 + (isr & DMA_CH1_TCIF != 0) as u32;
+        RX_HALVES.fetch_add(halves, Ordering::Relaxed);
+        dma.ifcr.write(|w| unsafe { w.bits(DMA_CH1_FLAGS) });
+    }
+
+    /// Also notices when the last block went out and frees up the channel.
+    /// With flow control the block just sits here while the host keeps CTS deasserted, and `uart_tx_q` isn't drained meanwhile.
+    /// Received radio packets wait in `RxPacketQueue` for room instead of getting ditched.
+    pub fn tx_busy(&mut self) -> bool {
+        if self.tx_busy {
+            let dma = unsafe { &(*pac::DMA1::PTR) };
+            if dma.isr.read().bits() & DMA_CH2_TCIF != 0 {
+                dma.ifcr.write(|w| unsafe { w.bits(DMA_CH2_FLAGS) });
+                dma.ccr2.write(|w| unsafe { w.bits(0) });
+                self.tx_busy = false;
+            }
+        }
+
+        self.tx_busy
+    }
+
+    /// Take the next block off the queue and start sending it, false if still busy or nothing to send
+    pub fn tx_start(&mut self, queue: &mut CacheQueue) -> bool {
+        if self.tx_busy() {
+            return false;
+        }
+
+        let block = ptr::addr_of_mut!(TX_BLOCK) as *mut u8;
+        let mut len: usize = 0;
+        while len < TX_BLOCK_LEN {
+            match queue.dequeue() {
+                Some(b) => unsafe { ptr::write_volatile(block.add(len), b) },
+                None => break,
+            }
+            len += 1;
+        }
+
+        if len == 0 {
+            return false;
+        }
+
+        unsafe {
+            let dma = &(*pac::DMA1::PTR);
+            dma.cndtr2.write(|w| w.bits(len as u32));
+            dma.ccr2.write(|w| w.bits(DMA_CCR_MINC | DMA_CCR_DIR_M2P | DMA_CCR_EN));
+        }
+
+        self.tx_busy = true;
+        true
+    }
+}
+
+/// Hand the RTS and CTS pins over to LPUART1, the HAL only knows about Rx and Tx
+unsafe fn enable_flow_control_pins() {
+    let gpioa = &(*pac::GPIOA::PTR);
+
+    gpioa.moder.modify(|r, w| {
+        let mut bits = r.bits();
+        for pin in [FLOW_RTS_PIN, FLOW_CTS_PIN] {
+            bits = (bits & !(0b11 << (pin * 2))) | (GPIO_MODE_AF << (pin * 2));
+        }
+        w.bits(bits)
+    });
+    gpioa.afrl.modify(|r, w| {
+        let mut bits = r.bits();
+        for pin in [FLOW_RTS_PIN, FLOW_CTS_PIN] {
+            bits = (bits & !(0xf << (pin * 4))) | (GPIO_AF8_LPUART1 << (pin * 4));
+        }
+        w.bits(bits)
+    });
+}
@@ -0,0 +1,50 @@
+use crate::{
+    constants::CacheQueue,
+    stats::{self, Stat},
+};
+
+/// A frame with no new byte for this long is stale, the host most likely died halfway through it
+pub const FRAME_TIMEOUT_MS: u32 = 100;
+
+/// Watches the frame being collected in `uart_rx_q` for gaps between bytes
+pub struct FrameTimer {
+    last_byte_ms: Option<u32>,
+}
+
+impl Default for FrameTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameTimer {
+    pub const fn new() -> FrameTimer {
+        FrameTimer { last_byte_ms: None }
+    }
+
+    pub fn byte_received(&mut self, now_ms: u32) {
+        self.last_byte_ms = Some(now_ms);
+    }
+
+    /// Returns true (and counts it) if the frame in `uart_rx_q` went stale, the caller throws it away then.
+    /// An empty queue means the last frame got decoded or dropped already, so there's nothing to time.
+    pub fn check_expired(&mut self, now_ms: u32, uart_rx_q: &CacheQueue) -> bool {
+        if uart_rx_q.is_empty() {
+            return false;
+        }
+
+        let last_byte_ms = match self.last_byte_ms {
+            Some(ms) => ms,
+            None => return false,
+        };
+
+        if now_ms.wrapping_sub(last_byte_ms) < FRAME_TIMEOUT_MS {
+            return false;
+        }
+
+        self.last_byte_ms = None;
+        stats::count(Stat::UartStaleFrame);
+        defmt::warn!("FrameTimer: stale frame dropped");
+        true
+    }
+}
@@ -0,0 +1,165 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use stm32wlxx_hal::pac;
+
+/// Reset if the firmware stops showing signs of life for this long, IWDG tops out at around 32s.
+/// Override with `LPLORA_WATCHDOG_TIMEOUT_MS` at build time.
+pub const WATCHDOG_TIMEOUT_MS: u32 = match option_env!("LPLORA_WATCHDOG_TIMEOUT_MS") {
+    Some(ms) => parse_timeout(ms),
+    None => 8000,
+};
+
+/// How often the heartbeats get checked, each of them has to show up in between for a feed
+pub const WATCHDOG_CHECK_MS: u32 = 2000;
+
+// Has to leave room for at least one missed check before it bites, and still fit PR=256
+const _: () = assert!(
+    WATCHDOG_TIMEOUT_MS > WATCHDOG_CHECK_MS * 2 && WATCHDOG_TIMEOUT_MS <= 32000,
+    "LPLORA_WATCHDOG_TIMEOUT_MS out of range"
+);
+
+const LSI_HZ: u32 = 32000;
+const IWDG_RELOAD_MAX: u32 = 0xfff;
+
+const IWDG_KEY_START: u32 = 0xcccc;
+const IWDG_KEY_UNLOCK: u32 = 0x5555;
+const IWDG_KEY_FEED: u32 = 0xaaaa;
+
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xcdef_89ab;
+const FLASH_OPT_KEY1: u32 = 0x0819_2a3b;
+const FLASH_OPT_KEY2: u32 = 0x4c5d_6e7f;
+// OPERR, PROGERR, WRPERR, PGAERR, SIZERR, PGSERR, MISSERR, FASTERR, RDERR and OPTVERR, all write 1 to clear
+const FLASH_SR_ERRORS: u32 = 0xc3fa;
+
+const fn parse_timeout(s: &str) -> u32 {
+    match u32::from_str_radix(s, 10) {
+        Ok(v) => v,
+        Err(_) => panic!("LPLORA_WATCHDOG_TIMEOUT_MS is not a number"),
+    }
+}
+
+// Set by whoever shows signs of life, cleared on every feed
+static UART_ALIVE: AtomicBool = AtomicBool::new(false);
+static RADIO_ALIVE: AtomicBool = AtomicBool::new(false);
+static IDLE_ALIVE: AtomicBool = AtomicBool::new(false);
+
+/// `uart_task` ran, so the UART side isn't stuck behind a lock or a higher priority task
+pub fn uart_alive() {
+    UART_ALIVE.store(true, Ordering::Relaxed);
+}
+
+/// Radio health check found the radio making progress (or with nothing to do)
+pub fn radio_alive() {
+    RADIO_ALIVE.store(true, Ordering::Relaxed);
+}
+
+/// Idle loop got to run, nothing is hogging the CPU
+pub fn idle_alive() {
+    IDLE_ALIVE.store(true, Ordering::Relaxed);
+}
+
+/// Get the IWDG_STOP option byte to match `watchdog-stop2` before anything else in `init`.
+/// With the feature the IWDG keeps counting in STOP2, so the host has to wake the module up within the timeout.
+/// Otherwise it's frozen there and a module put to sleep on purpose stays asleep.
+/// Changing it resets the MCU once, so this has to run before the reset flags get read and cleared,
+/// or the real cause of the boot is lost behind the option byte load.
+/// Returns false if the option byte is still wrong, the IWDG then keeps the other STOP2 behavior.
+pub fn apply_watchdog_option() -> bool {
+    let run_in_stop = cfg!(feature = "watchdog-stop2");
+    if unsafe { (*pac::FLASH::PTR).optr.read().iwdg_stop().bit() } == run_in_stop {
+        return true;
+    }
+
+    // Flags get cleared every boot, so this boot came from our own option byte load and it didn't stick.
+    // Trying again would only reset over and over.
+    if unsafe { (*pac::RCC::PTR).csr.read().oblrstf().bit_is_set() } {
+        defmt::error!(
+            "apply_watchdog_option: IWDG_STOP still not {} after reload, giving up",
+            run_in_stop
+        );
+        return false;
+    }
+
+    set_iwdg_stop_option(run_in_stop)
+}
+
+/// Start the IWDG, it can't be stopped again until the next reset
+pub fn start_watchdog(timeout_ms: u32) {
+    // Smallest prescaler (4, 8, ... 256) that still fits the timeout into the reload register
+    let mut prescaler: u32 = 0;
+    let mut reload = timeout_ms * (LSI_HZ / 1000) / 4;
+    while reload > IWDG_RELOAD_MAX && prescaler < 6 {
+        prescaler += 1;
+        reload /= 2;
+    }
+    let reload = reload.clamp(1, IWDG_RELOAD_MAX);
+
+    unsafe {
+        let iwdg = &(*pac::IWDG::PTR);
+        iwdg.kr.write(|w| w.bits(IWDG_KEY_START));
+        iwdg.kr.write(|w| w.bits(IWDG_KEY_UNLOCK));
+        iwdg.pr.write(|w| w.bits(prescaler));
+        iwdg.rlr.write(|w| w.bits(reload));
+        while iwdg.sr.read().bits() != 0 {}
+        iwdg.kr.write(|w| w.bits(IWDG_KEY_FEED));
+    }
+
+    defmt::info!("start_watchdog: {}ms, PR={}, RLR={}", timeout_ms, prescaler, reload);
+}
+
+/// Feed only if everyone checked in since the last time, returns false if someone didn't
+pub fn feed_watchdog() -> bool {
+    let uart = UART_ALIVE.swap(false, Ordering::Relaxed);
+    let radio = RADIO_ALIVE.swap(false, Ordering::Relaxed);
+    let idle = IDLE_ALIVE.swap(false, Ordering::Relaxed);
+
+    if !(uart && radio && idle) {
+        defmt::warn!("feed_watchdog: missed, uart={}, radio={}, idle={}", uart, radio, idle);
+        return false;
+    }
+
+    unsafe { (*pac::IWDG::PTR).kr.write(|w| w.bits(IWDG_KEY_FEED)) };
+    true
+}
+
+/// Feed unconditionally, only for right before going into STOP2 where nothing else runs
+pub fn feed_watchdog_now() {
+    unsafe { (*pac::IWDG::PTR).kr.write(|w| w.bits(IWDG_KEY_FEED)) };
+}
+
+/// IWDG_STOP option byte: set keeps the IWDG counting in STOP, clear freezes it.
+/// Only returns if programming failed, otherwise the option bytes get reloaded with a reset.
+fn set_iwdg_stop_option(run_in_stop: bool) -> bool {
+    let flash = unsafe { &(*pac::FLASH::PTR) };
+
+    defmt::warn!("set_iwdg_stop_option: IWDG_STOP -> {}, resetting", run_in_stop);
+    unsafe {
+        flash.keyr.write(|w| w.bits(FLASH_KEY1));
+        flash.keyr.write(|w| w.bits(FLASH_KEY2));
+        flash.optkeyr.write(|w| w.bits(FLASH_OPT_KEY1));
+        flash.optkeyr.write(|w| w.bits(FLASH_OPT_KEY2));
+    }
+
+    // Leftovers from before (e.g. the bootloader) would make OPTSTRT fail straight away
+    while flash.sr.read().bsy().bit_is_set() {}
+    flash.sr.write(|w| unsafe { w.bits(FLASH_SR_ERRORS) });
+    flash.optr.modify(|_, w| w.iwdg_stop().bit(run_in_stop));
+    flash.cr.modify(|_, w| w.optstrt().set_bit());
+    while flash.sr.read().bsy().bit_is_set() {}
+
+    // Launching now would only load the old value back and reset again, so stay with it for this boot
+    let errors = flash.sr.read().bits() & FLASH_SR_ERRORS;
+    if errors != 0 {
+        defmt::error!("set_iwdg_stop_option: programming failed, FLASH_SR=0x{:x}", errors);
+        flash.sr.write(|w| unsafe { w.bits(errors) });
+        flash.cr.modify(|_, w| w.optlock().set_bit().lock().set_bit());
+        return false;
+    }
+
+    // Never returns, the new option bytes get loaded with a reset
+    flash.cr.modify(|_, w| w.obl_launch().set_bit());
+    loop {
+        cortex_m::asm::nop();
+    }
+}